@@ -0,0 +1,117 @@
+use std::sync::Mutex;
+use super::{AudioBackend, AudioDevice, AudioSession, DeviceKind};
+
+/// A `set_volume` or `set_mute` the mock received.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockCall {
+    SetVolume { session: String, volume: f32 },
+    SetMute { session: String, muted: bool },
+    SetDeviceVolume { device: String, volume: f32 },
+    SetDeviceMute { device: String, muted: bool },
+    SetDefaultOutput { device: String },
+}
+
+/// Backend that serves scripted sessions and records every change, so the
+/// device loop can run without a sound server.
+pub struct MockBackend {
+    sessions: Mutex<Vec<AudioSession>>,
+    devices: Mutex<Vec<AudioDevice>>,
+    calls: Mutex<Vec<MockCall>>,
+}
+
+impl MockBackend {
+    pub fn new(sessions: Vec<AudioSession>, devices: Vec<AudioDevice>) -> Self {
+        Self {
+            sessions: Mutex::new(sessions),
+            devices: Mutex::new(devices),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// One session per application name plus a default output and input,
+    /// all at `volume`.
+    pub fn with_apps<I: IntoIterator<Item = String>>(apps: I, volume: f32) -> Self {
+        let sessions = apps.into_iter().enumerate().map(|(i, name)| AudioSession {
+            id: i.to_string(),
+            name,
+            pid: None,
+            path: None,
+            display_name: None,
+            volume,
+            muted: false,
+        }).collect();
+        let device = |id: &str, kind| AudioDevice {
+            id: id.to_string(),
+            name: id.to_string(),
+            kind,
+            is_default: true,
+            volume,
+            muted: false,
+        };
+        Self::new(sessions, vec![device("mock-output", DeviceKind::Output), device("mock-input", DeviceKind::Input)])
+    }
+
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut AudioSession)) -> Result<(), anyhow::Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.iter_mut().find(|s| s.id == id)
+            .ok_or_else(|| anyhow::Error::msg("Audio session is gone!"))?;
+        change(session);
+        Ok(())
+    }
+
+    fn update_device(&self, id: &str, change: impl FnOnce(&mut AudioDevice)) -> Result<(), anyhow::Error> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.iter_mut().find(|d| d.id == id)
+            .ok_or_else(|| anyhow::Error::msg("Audio device is gone!"))?;
+        change(device);
+        Ok(())
+    }
+}
+
+impl AudioBackend for MockBackend {
+    fn sessions(&self) -> Result<Vec<AudioSession>, anyhow::Error> {
+        Ok(self.sessions.lock().unwrap().clone())
+    }
+
+    fn set_volume(&self, session: &AudioSession, volume: f32) -> Result<(), anyhow::Error> {
+        self.calls.lock().unwrap().push(MockCall::SetVolume { session: session.name.clone(), volume });
+        self.update(&session.id, |s| s.volume = volume)
+    }
+
+    fn set_mute(&self, session: &AudioSession, muted: bool) -> Result<(), anyhow::Error> {
+        self.calls.lock().unwrap().push(MockCall::SetMute { session: session.name.clone(), muted });
+        self.update(&session.id, |s| s.muted = muted)
+    }
+
+    fn devices(&self) -> Result<Vec<AudioDevice>, anyhow::Error> {
+        Ok(self.devices.lock().unwrap().clone())
+    }
+
+    fn set_device_volume(&self, device: &AudioDevice, volume: f32) -> Result<(), anyhow::Error> {
+        self.calls.lock().unwrap().push(MockCall::SetDeviceVolume { device: device.name.clone(), volume });
+        self.update_device(&device.id, |d| d.volume = volume)
+    }
+
+    fn set_device_mute(&self, device: &AudioDevice, muted: bool) -> Result<(), anyhow::Error> {
+        self.calls.lock().unwrap().push(MockCall::SetDeviceMute { device: device.name.clone(), muted });
+        self.update_device(&device.id, |d| d.muted = muted)
+    }
+
+    fn set_default_output(&self, device: &AudioDevice) -> Result<(), anyhow::Error> {
+        self.calls.lock().unwrap().push(MockCall::SetDefaultOutput { device: device.name.clone() });
+        let mut devices = self.devices.lock().unwrap();
+        for d in devices.iter_mut().filter(|d| d.kind == DeviceKind::Output) {
+            d.is_default = d.id == device.id;
+        }
+        Ok(())
+    }
+
+    /// Scripted sessions never make a sound.
+    fn playing(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,313 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use regex::Regex;
+use serde::Serialize;
+use crate::foreground;
+use crate::mapping::{MatchBy, Target, TypedTarget};
+use crate::packet::Direction;
+use crate::volume::Level;
+
+pub mod mock;
+mod registry;
+#[cfg(target_os = "linux")]
+mod pulse;
+#[cfg(windows)]
+mod policy_config;
+#[cfg(windows)]
+mod wasapi;
+
+/// One application's audio stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSession {
+    /// Backend specific handle, only meaningful to the backend that listed it.
+    pub id: String,
+    /// Executable name of the owning process.
+    pub name: String,
+    pub pid: Option<u32>,
+    /// Full path of the owning process' executable, when it can be read.
+    pub path: Option<String>,
+    /// Name the application gave the stream, if any.
+    pub display_name: Option<String>,
+    /// Between 0.0 and 1.0.
+    pub volume: f32,
+    pub muted: bool,
+}
+
+/// Lowercased and without the `.exe` Windows puts on every executable, the
+/// way mappings store application names.
+pub fn executable_stem(name: &str) -> String {
+    let name = name.to_lowercase();
+    if cfg!(windows) {
+        if let Some(stem) = name.strip_suffix(".exe") {
+            return stem.to_string();
+        }
+    }
+    name
+}
+
+/// A compiled `Application` target.
+pub enum AppMatcher {
+    Executable(String),
+    Path(String),
+    Regex(Regex),
+    DisplayName(String),
+}
+
+impl AppMatcher {
+    pub fn new(by: MatchBy, pattern: &str) -> Result<Self, anyhow::Error> {
+        Ok(match by {
+            MatchBy::Executable => AppMatcher::Executable(executable_stem(pattern)),
+            MatchBy::Path => AppMatcher::Path(pattern.to_string()),
+            MatchBy::Regex => AppMatcher::Regex(Regex::new(pattern)?),
+            MatchBy::DisplayName => AppMatcher::DisplayName(pattern.to_lowercase()),
+        })
+    }
+
+    pub fn matches(&self, session: &AudioSession) -> bool {
+        match self {
+            AppMatcher::Executable(stem) => executable_stem(&session.name) == *stem,
+            // paths are case insensitive on Windows only
+            AppMatcher::Path(path) => session.path.as_ref().map_or(false, |p| {
+                if cfg!(windows) { p.eq_ignore_ascii_case(path) } else { p == path }
+            }),
+            AppMatcher::Regex(regex) => std::iter::once(Some(&session.name))
+                .chain([session.path.as_ref(), session.display_name.as_ref()])
+                .flatten()
+                .any(|value| regex.is_match(value)),
+            AppMatcher::DisplayName(name) => session.display_name.as_ref().map_or(false, |d| d.to_lowercase() == *name),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Output,
+    Input,
+}
+
+/// A whole output or input device (an endpoint, sink or source).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AudioDevice {
+    /// Backend specific and stable across restarts.
+    pub id: String,
+    /// Human readable, what the OS sound settings show.
+    pub name: String,
+    pub kind: DeviceKind,
+    pub is_default: bool,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl AudioDevice {
+    pub fn matches(&self, device: &str) -> bool {
+        self.id == device || self.name.eq_ignore_ascii_case(device)
+    }
+}
+
+/// Per-application and per-device volume control on whatever sound system
+/// the OS has.
+pub trait AudioBackend {
+    fn sessions(&self) -> Result<Vec<AudioSession>, anyhow::Error>;
+    fn set_volume(&self, session: &AudioSession, volume: f32) -> Result<(), anyhow::Error>;
+    fn set_mute(&self, session: &AudioSession, muted: bool) -> Result<(), anyhow::Error>;
+    /// Active output and input devices.
+    fn devices(&self) -> Result<Vec<AudioDevice>, anyhow::Error>;
+    fn set_device_volume(&self, device: &AudioDevice, volume: f32) -> Result<(), anyhow::Error>;
+    fn set_device_mute(&self, device: &AudioDevice, muted: bool) -> Result<(), anyhow::Error>;
+    /// Makes an output device the one new and moved streams play on.
+    fn set_default_output(&self, device: &AudioDevice) -> Result<(), anyhow::Error>;
+    /// Ids of the sessions from the last listing that are making sound.
+    fn playing(&self) -> Result<Vec<String>, anyhow::Error>;
+
+    /// Sets `stale` whenever sessions appear, expire or change outside of this
+    /// backend, until the backend is dropped. Backends that can't tell return
+    /// an error.
+    fn subscribe(&self, _stale: Arc<AtomicBool>) -> Result<(), anyhow::Error> {
+        Err(anyhow::Error::msg("Session notifications are not supported!"))
+    }
+}
+
+/// Anything a mapping can resolve to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    Session(AudioSession),
+    Device(AudioDevice),
+}
+
+impl Control {
+    /// Backend id of the session or device.
+    pub fn id(&self) -> &str {
+        match self {
+            Control::Session(session) => &session.id,
+            Control::Device(device) => &device.id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Control::Session(session) => &session.name,
+            Control::Device(device) => &device.name,
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        match self {
+            Control::Session(session) => session.volume,
+            Control::Device(device) => device.volume,
+        }
+    }
+
+    pub fn muted(&self) -> bool {
+        match self {
+            Control::Session(session) => session.muted,
+            Control::Device(device) => device.muted,
+        }
+    }
+
+    /// What is actually heard, 0.0 while muted.
+    pub fn level(&self) -> f32 {
+        if self.muted() { 0.0 } else { self.volume() }
+    }
+
+    pub fn set_volume(&self, backend: &dyn AudioBackend, volume: f32) -> Result<(), anyhow::Error> {
+        match self {
+            Control::Session(session) => backend.set_volume(session, volume),
+            Control::Device(device) => backend.set_device_volume(device, volume),
+        }
+    }
+
+    pub fn set_mute(&self, backend: &dyn AudioBackend, muted: bool) -> Result<(), anyhow::Error> {
+        match self {
+            Control::Session(session) => backend.set_mute(session, muted),
+            Control::Device(device) => backend.set_device_mute(device, muted),
+        }
+    }
+
+    /// Sets the volume, and the mute state when it differs.
+    pub fn apply(&self, backend: &dyn AudioBackend, level: Level) -> Result<(), anyhow::Error> {
+        self.set_volume(backend, level.volume)?;
+        if self.muted() != level.muted {
+            self.set_mute(backend, level.muted)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything `target` currently points at, empty when nothing matches.
+pub fn resolve(backend: &dyn AudioBackend, target: &Target) -> Result<Vec<Control>, anyhow::Error> {
+    let device_matches = |device: &AudioDevice| match target {
+        Target::App(_) | Target::Typed(TypedTarget::Application { .. } | TypedTarget::ForegroundApp) => false,
+        Target::Typed(TypedTarget::DeviceMaster { device: name }) => device.kind == DeviceKind::Output && device.matches(name),
+        Target::Typed(TypedTarget::DefaultOutput) => device.kind == DeviceKind::Output && device.is_default,
+        Target::Typed(TypedTarget::DefaultInput) => device.kind == DeviceKind::Input && device.is_default,
+        Target::Typed(TypedTarget::CaptureDevice { device: name }) => device.kind == DeviceKind::Input && device.matches(name),
+        Target::Typed(TypedTarget::CycleOutput { .. } | TypedTarget::SwitchOutput { .. } | TypedTarget::Group { .. } | TypedTarget::Scene { .. }) => false,
+    };
+    let controls = match target {
+        Target::App(application) => {
+            let matcher = AppMatcher::Executable(executable_stem(application));
+            backend.sessions()?.into_iter()
+                .filter(|s| matcher.matches(s))
+                .map(Control::Session)
+                .collect()
+        }
+        Target::Typed(TypedTarget::Application { by, pattern }) => {
+            let matcher = AppMatcher::new(*by, pattern)?;
+            backend.sessions()?.into_iter()
+                .filter(|s| matcher.matches(s))
+                .map(Control::Session)
+                .collect()
+        }
+        Target::Typed(TypedTarget::ForegroundApp) => {
+            let mut foreground = foreground::Foreground::new()?;
+            backend.sessions()?.into_iter()
+                .filter(|s| s.pid.map_or(false, |pid| foreground.contains(pid)))
+                .map(Control::Session)
+                .collect()
+        }
+        Target::Typed(TypedTarget::Group { apps, .. }) => {
+            let mut controls: Vec<Control> = Vec::new();
+            for app in apps {
+                for control in resolve(backend, app)? {
+                    // two members can match the same session
+                    if !controls.contains(&control) {
+                        controls.push(control);
+                    }
+                }
+            }
+            controls
+        }
+        Target::Typed(_) => backend.devices()?.into_iter()
+            .filter(device_matches)
+            .map(Control::Device)
+            .collect(),
+    };
+    Ok(controls)
+}
+
+/// Level of the loudest control, what a key with several controls reports.
+/// Muted controls count as silent.
+pub fn loudest(controls: &[Control]) -> Option<f32> {
+    controls.iter().map(|c| c.level()).reduce(f32::max)
+}
+
+/// Current default output, if there is one.
+pub fn default_output(backend: &dyn AudioBackend) -> Result<Option<AudioDevice>, anyhow::Error> {
+    Ok(backend.devices()?.into_iter().find(|d| d.kind == DeviceKind::Output && d.is_default))
+}
+
+/// Makes the output after the current default in `order` the new default,
+/// or the one before it going down. Names in `order` that aren't plugged in
+/// are skipped, an empty `order` means every active output.
+pub fn cycle_output(backend: &dyn AudioBackend, order: &[String], direction: Direction) -> Result<AudioDevice, anyhow::Error> {
+    let outputs: Vec<AudioDevice> = backend.devices()?.into_iter().filter(|d| d.kind == DeviceKind::Output).collect();
+    let candidates: Vec<&AudioDevice> = if order.is_empty() {
+        outputs.iter().collect()
+    } else {
+        order.iter().filter_map(|name| outputs.iter().find(|d| d.matches(name))).collect()
+    };
+    if candidates.is_empty() {
+        return Err(anyhow::Error::msg("None of the outputs to cycle through are available!"));
+    }
+
+    let len = candidates.len();
+    let next = match (candidates.iter().position(|d| d.is_default), direction) {
+        (Some(current), Direction::Up) => (current + 1) % len,
+        (Some(current), Direction::Down) => (current + len - 1) % len,
+        (None, Direction::Up) => 0,
+        (None, Direction::Down) => len - 1,
+    };
+    let device = candidates[next].clone();
+    backend.set_default_output(&device)?;
+    Ok(device)
+}
+
+pub fn switch_output(backend: &dyn AudioBackend, name: &str) -> Result<AudioDevice, anyhow::Error> {
+    let device = backend.devices()?.into_iter()
+        .find(|d| d.kind == DeviceKind::Output && d.matches(name))
+        .ok_or_else(|| anyhow::Error::msg(format!("Output {} is not available!", name)))?;
+    backend.set_default_output(&device)?;
+    Ok(device)
+}
+
+/// The backend for this platform behind a session cache. Backends aren't
+/// `Send`, create one on the thread that uses it and keep it around.
+pub fn default_backend() -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+    Ok(Box::new(registry::SessionRegistry::new(platform_backend()?)))
+}
+
+/// The bare backend for this platform, for one-off listings.
+#[cfg(windows)]
+pub fn platform_backend() -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+    Ok(Box::new(wasapi::WasapiBackend::new()?))
+}
+
+#[cfg(target_os = "linux")]
+pub fn platform_backend() -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+    Ok(Box::new(pulse::PulseBackend::new()?))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn platform_backend() -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+    Err(anyhow::Error::msg("No audio backend for this platform!"))
+}
@@ -0,0 +1,26 @@
+#![allow(non_snake_case)]
+//! `IPolicyConfig`, the undocumented interface the Windows sound settings use
+//! to change the default endpoint. It has kept this shape since Windows 7.
+
+use std::ffi::c_void;
+use windows::core::{interface, IUnknown, IUnknown_Vtbl, GUID, HRESULT, PCWSTR};
+use windows::Win32::Media::Audio::ERole;
+
+pub const CPOLICY_CONFIG_CLIENT: GUID = GUID::from_u128(0x870af99c_171d_4f9e_af0d_e63df40c2bc9);
+
+// only SetDefaultEndpoint is used, the rest just has to keep the vtable in order
+#[interface("f8679f50-850a-41cf-9c72-430f290290c8")]
+pub unsafe trait IPolicyConfig: IUnknown {
+    pub fn GetMixFormat(&self, device: PCWSTR, format: *mut *mut c_void) -> HRESULT;
+    pub fn GetDeviceFormat(&self, device: PCWSTR, default: i32, format: *mut *mut c_void) -> HRESULT;
+    pub fn ResetDeviceFormat(&self, device: PCWSTR) -> HRESULT;
+    pub fn SetDeviceFormat(&self, device: PCWSTR, endpoint_format: *mut c_void, mix_format: *mut c_void) -> HRESULT;
+    pub fn GetProcessingPeriod(&self, device: PCWSTR, default: i32, default_period: *mut i64, minimum_period: *mut i64) -> HRESULT;
+    pub fn SetProcessingPeriod(&self, device: PCWSTR, period: *mut i64) -> HRESULT;
+    pub fn GetShareMode(&self, device: PCWSTR, mode: *mut c_void) -> HRESULT;
+    pub fn SetShareMode(&self, device: PCWSTR, mode: *mut c_void) -> HRESULT;
+    pub fn GetPropertyValue(&self, device: PCWSTR, key: *const c_void, value: *mut c_void) -> HRESULT;
+    pub fn SetPropertyValue(&self, device: PCWSTR, key: *const c_void, value: *mut c_void) -> HRESULT;
+    pub fn SetDefaultEndpoint(&self, device: PCWSTR, role: ERole) -> HRESULT;
+    pub fn SetEndpointVisibility(&self, device: PCWSTR, visible: i32) -> HRESULT;
+}
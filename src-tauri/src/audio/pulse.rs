@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde_json::Value;
+use super::{AudioBackend, AudioDevice, AudioSession, DeviceKind};
+
+// pulse volumes are fixed point with 0x10000 meaning 100%
+const VOLUME_NORM: f32 = 65536.0;
+
+/// Sink inputs, sinks and sources on PulseAudio, or PipeWire through
+/// pipewire-pulse.
+///
+/// Talks to the server through `pactl`, which needs pactl 16 or newer for
+/// JSON output.
+pub struct PulseBackend {
+    /// `pactl subscribe` while someone is subscribed.
+    subscription: RefCell<Option<Child>>,
+}
+
+impl PulseBackend {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        pactl(&["info"])?;
+        Ok(Self {
+            subscription: RefCell::new(None),
+        })
+    }
+}
+
+impl Drop for PulseBackend {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.subscription.borrow_mut().take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn pactl(args: &[&str]) -> Result<String, anyhow::Error> {
+    let output = Command::new("pactl").args(args).output()?;
+    if !output.status.success() {
+        return Err(anyhow::Error::msg(format!("pactl {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Average over all channels, pactl reports each one separately.
+fn average_volume(volume: &Value) -> f32 {
+    let channels: Vec<f32> = volume.as_object()
+        .map(|channels| channels.values().filter_map(|c| c["value"].as_f64()).map(|v| v as f32 / VOLUME_NORM).collect())
+        .unwrap_or_default();
+    if channels.is_empty() {
+        return 0.0;
+    }
+    channels.iter().sum::<f32>() / channels.len() as f32
+}
+
+fn parse_sink_input(input: &Value) -> Option<AudioSession> {
+    let index = input["index"].as_u64()?;
+    let properties = &input["properties"];
+    let name = properties["application.process.binary"].as_str()
+        .or_else(|| properties["application.name"].as_str())?;
+    let pid: Option<u32> = properties["application.process.id"].as_str().and_then(|p| p.parse().ok());
+    Some(AudioSession {
+        id: index.to_string(),
+        name: name.to_string(),
+        pid,
+        // sandboxed clients report a pid from another namespace, so this can miss
+        path: pid.and_then(|pid| std::fs::read_link(format!("/proc/{}/exe", pid)).ok())
+            .map(|path| path.to_string_lossy().to_string()),
+        display_name: properties["application.name"].as_str().map(|n| n.to_string()),
+        volume: average_volume(&input["volume"]),
+        muted: input["mute"].as_bool().unwrap_or(false),
+    })
+}
+
+fn parse_device(device: &Value, kind: DeviceKind, default: Option<&str>) -> Option<AudioDevice> {
+    let id = device["name"].as_str()?;
+    // every sink has a monitor source, those aren't microphones
+    if device["monitor_of_sink"].as_str().map_or(false, |sink| sink != "n/a") {
+        return None;
+    }
+    Some(AudioDevice {
+        id: id.to_string(),
+        name: device["description"].as_str().unwrap_or(id).to_string(),
+        kind,
+        is_default: default == Some(id),
+        volume: average_volume(&device["volume"]),
+        muted: device["mute"].as_bool().unwrap_or(false),
+    })
+}
+
+/// Lines from `pactl subscribe` that can change what `sessions` returns.
+/// Streams report a `change` on every volume tweak and position update, and
+/// the cache already has our own, so only streams coming and going count,
+/// plus server changes, which is how a new default sink shows up.
+fn changes_sessions(event: &str) -> bool {
+    // "Event 'new' on sink-input #42"
+    event.starts_with("Event 'new' on sink-input")
+        || event.starts_with("Event 'remove' on sink-input")
+        || event.contains(" on server")
+}
+
+fn raw_volume(volume: f32) -> String {
+    ((volume.clamp(0.0, 1.0) * VOLUME_NORM).round() as u32).to_string()
+}
+
+impl AudioBackend for PulseBackend {
+    fn sessions(&self) -> Result<Vec<AudioSession>, anyhow::Error> {
+        let listing: Value = serde_json::from_str(&pactl(&["-f", "json", "list", "sink-inputs"])?)?;
+        let sessions = listing.as_array()
+            .map(|inputs| inputs.iter().filter_map(parse_sink_input).collect())
+            .unwrap_or_default();
+        Ok(sessions)
+    }
+
+    fn set_volume(&self, session: &AudioSession, volume: f32) -> Result<(), anyhow::Error> {
+        pactl(&["set-sink-input-volume", &session.id, &raw_volume(volume)])?;
+        Ok(())
+    }
+
+    fn set_mute(&self, session: &AudioSession, muted: bool) -> Result<(), anyhow::Error> {
+        pactl(&["set-sink-input-mute", &session.id, if muted { "1" } else { "0" }])?;
+        Ok(())
+    }
+
+    fn devices(&self) -> Result<Vec<AudioDevice>, anyhow::Error> {
+        let info: Value = serde_json::from_str(&pactl(&["-f", "json", "info"])?)?;
+        let mut devices = Vec::new();
+        for (list, kind, default) in [("sinks", DeviceKind::Output, "default_sink_name"), ("sources", DeviceKind::Input, "default_source_name")] {
+            let listing: Value = serde_json::from_str(&pactl(&["-f", "json", "list", list])?)?;
+            if let Some(entries) = listing.as_array() {
+                devices.extend(entries.iter().filter_map(|d| parse_device(d, kind, info[default].as_str())));
+            }
+        }
+        Ok(devices)
+    }
+
+    fn set_device_volume(&self, device: &AudioDevice, volume: f32) -> Result<(), anyhow::Error> {
+        let command = match device.kind {
+            DeviceKind::Output => "set-sink-volume",
+            DeviceKind::Input => "set-source-volume",
+        };
+        pactl(&[command, &device.id, &raw_volume(volume)])?;
+        Ok(())
+    }
+
+    fn set_device_mute(&self, device: &AudioDevice, muted: bool) -> Result<(), anyhow::Error> {
+        let command = match device.kind {
+            DeviceKind::Output => "set-sink-mute",
+            DeviceKind::Input => "set-source-mute",
+        };
+        pactl(&[command, &device.id, if muted { "1" } else { "0" }])?;
+        Ok(())
+    }
+
+    fn set_default_output(&self, device: &AudioDevice) -> Result<(), anyhow::Error> {
+        pactl(&["set-default-sink", &device.id])?;
+        // plain PulseAudio leaves running streams where they are
+        for session in self.sessions()? {
+            if let Err(e) = pactl(&["move-sink-input", &session.id, &device.id]) {
+                println!("Cannot move {} to {}: {}", session.name, device.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams that aren't corked, PulseAudio has no cheap peak meter.
+    fn playing(&self) -> Result<Vec<String>, anyhow::Error> {
+        let listing: Value = serde_json::from_str(&pactl(&["-f", "json", "list", "sink-inputs"])?)?;
+        let playing = listing.as_array()
+            .map(|inputs| inputs.iter()
+                .filter(|input| input["corked"].as_bool() == Some(false))
+                .filter_map(|input| input["index"].as_u64())
+                .map(|index| index.to_string())
+                .collect())
+            .unwrap_or_default();
+        Ok(playing)
+    }
+
+    fn subscribe(&self, stale: Arc<AtomicBool>) -> Result<(), anyhow::Error> {
+        let mut child = Command::new("pactl")
+            .arg("subscribe")
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow::Error::msg("pactl subscribe has no output!"))?;
+        // output ends when the server goes away or we drop the child
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if changes_sessions(&line) {
+                    stale.store(true, Ordering::SeqCst);
+                }
+            }
+            // make the next listing find out
+            stale.store(true, Ordering::SeqCst);
+        });
+        if let Some(mut previous) = self.subscription.borrow_mut().replace(child) {
+            let _ = previous.kill();
+            let _ = previous.wait();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::{AudioBackend, AudioDevice, AudioSession};
+
+/// Keeps the session list of another backend between key presses.
+///
+/// The list is only fetched again once the backend's change notifications
+/// mark it stale, so turning an encoder is a lookup instead of a full
+/// enumeration. Our own volume changes are applied to the cached copy.
+pub struct SessionRegistry {
+    inner: Box<dyn AudioBackend>,
+    sessions: RefCell<Option<Vec<AudioSession>>>,
+    stale: Arc<AtomicBool>,
+    /// Whether `inner` sets `stale`, the cache isn't kept otherwise.
+    subscribed: bool,
+}
+
+impl SessionRegistry {
+    pub fn new(inner: Box<dyn AudioBackend>) -> Self {
+        let stale = Arc::new(AtomicBool::new(true));
+        let subscribed = match inner.subscribe(stale.clone()) {
+            Ok(()) => true,
+            Err(e) => {
+                // without notifications the cache can't be trusted, so never keep it
+                println!("No audio session notifications, listing every time: {}", e);
+                false
+            }
+        };
+        Self {
+            inner,
+            sessions: RefCell::new(None),
+            stale,
+            subscribed,
+        }
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut AudioSession)) {
+        if let Some(sessions) = self.sessions.borrow_mut().as_mut() {
+            if let Some(session) = sessions.iter_mut().find(|s| s.id == id) {
+                change(session);
+            }
+        }
+    }
+}
+
+impl AudioBackend for SessionRegistry {
+    fn sessions(&self) -> Result<Vec<AudioSession>, anyhow::Error> {
+        // clear the flag first so a change during the listing isn't lost
+        let stale = self.stale.swap(false, Ordering::SeqCst) || !self.subscribed;
+        if let (false, Some(sessions)) = (stale, self.sessions.borrow().as_ref()) {
+            return Ok(sessions.clone());
+        }
+        let sessions = self.inner.sessions()?;
+        *self.sessions.borrow_mut() = Some(sessions.clone());
+        Ok(sessions)
+    }
+
+    fn set_volume(&self, session: &AudioSession, volume: f32) -> Result<(), anyhow::Error> {
+        self.inner.set_volume(session, volume)?;
+        self.update(&session.id, |s| s.volume = volume);
+        Ok(())
+    }
+
+    fn set_mute(&self, session: &AudioSession, muted: bool) -> Result<(), anyhow::Error> {
+        self.inner.set_mute(session, muted)?;
+        self.update(&session.id, |s| s.muted = muted);
+        Ok(())
+    }
+
+    fn devices(&self) -> Result<Vec<AudioDevice>, anyhow::Error> {
+        self.inner.devices()
+    }
+
+    fn set_device_volume(&self, device: &AudioDevice, volume: f32) -> Result<(), anyhow::Error> {
+        self.inner.set_device_volume(device, volume)
+    }
+
+    fn set_device_mute(&self, device: &AudioDevice, muted: bool) -> Result<(), anyhow::Error> {
+        self.inner.set_device_mute(device, muted)
+    }
+
+    fn set_default_output(&self, device: &AudioDevice) -> Result<(), anyhow::Error> {
+        self.inner.set_default_output(device)?;
+        // sessions are listed from the default output
+        self.stale.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn playing(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.inner.playing()
+    }
+}
@@ -0,0 +1,406 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use sysinfo::{Pid, ProcessExt, System, SystemExt, PidExt};
+
+
+use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+use windows::Win32::Foundation::BOOL;
+use windows::Win32::Media::Audio::{IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator, EDataFlow, ERole, eCapture, eRender, eMultimedia, eConsole, eCommunications, IAudioSessionManager2, IAudioSessionControl, IAudioSessionControl2, ISimpleAudioVolume, DEVICE_STATE_ACTIVE};
+use windows::Win32::Media::Audio::{IAudioSessionNotification, IAudioSessionNotification_Impl, IAudioSessionEvents, IAudioSessionEvents_Impl, IMMNotificationClient, IMMNotificationClient_Impl, AudioSessionState, AudioSessionDisconnectReason};
+use windows::Win32::Media::Audio::Endpoints::{IAudioEndpointVolume, IAudioMeterInformation};
+use windows::Win32::System::Com::{self, CoInitializeEx, CoTaskMemFree, COINIT_MULTITHREADED, STGM_READ};
+use windows::Win32::System::Com::StructuredStorage::PropVariantClear;
+use windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY;
+
+use windows::core::{implement, Interface, GUID, HSTRING, PCWSTR};
+use super::{AudioBackend, AudioDevice, AudioSession, DeviceKind};
+use super::policy_config::{IPolicyConfig, CPOLICY_CONFIG_CLIENT};
+
+unsafe fn device_id(device: &IMMDevice) -> Result<String, anyhow::Error> {
+    let raw = device.GetId()?;
+    let id = raw.to_string();
+    CoTaskMemFree(Some(raw.0 as *const _));
+    Ok(id?)
+}
+
+unsafe fn friendly_name(device: &IMMDevice) -> Result<String, anyhow::Error> {
+    let store = device.OpenPropertyStore(STGM_READ)?;
+    let mut value = store.GetValue(&PKEY_Device_FriendlyName)?;
+    let name = value.Anonymous.Anonymous.Anonymous.pwszVal.to_string();
+    PropVariantClear(&mut value)?;
+    Ok(name?)
+}
+
+// peak meter reading below which a session counts as silent
+const PEAK_THRESHOLD: f32 = 0.001;
+
+/// Raises the stale flag of a subscription. Called on COM worker threads.
+#[implement(IAudioSessionNotification, IAudioSessionEvents, IMMNotificationClient)]
+struct Watcher {
+    stale: Arc<AtomicBool>,
+    // event context of the owning backend's changes, which it already knows about
+    own_change: GUID,
+}
+
+impl Watcher {
+    fn changed(&self) -> windows::core::Result<()> {
+        self.stale.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn changed_by(&self, context: *const GUID) -> windows::core::Result<()> {
+        if context.is_null() || unsafe { *context } != self.own_change {
+            self.changed()?;
+        }
+        Ok(())
+    }
+}
+
+impl IAudioSessionNotification_Impl for Watcher {
+    fn OnSessionCreated(&self, _session: &Option<IAudioSessionControl>) -> windows::core::Result<()> {
+        self.changed()
+    }
+}
+
+impl IAudioSessionEvents_Impl for Watcher {
+    fn OnDisplayNameChanged(&self, _name: &PCWSTR, context: *const GUID) -> windows::core::Result<()> {
+        self.changed_by(context)
+    }
+
+    fn OnIconPathChanged(&self, _path: &PCWSTR, _context: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnSimpleVolumeChanged(&self, _volume: f32, _muted: BOOL, context: *const GUID) -> windows::core::Result<()> {
+        self.changed_by(context)
+    }
+
+    fn OnChannelVolumeChanged(&self, _count: u32, _volumes: *const f32, _channel: u32, _context: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnGroupingParamChanged(&self, _grouping: *const GUID, _context: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnStateChanged(&self, _state: AudioSessionState) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnSessionDisconnected(&self, _reason: AudioSessionDisconnectReason) -> windows::core::Result<()> {
+        self.changed()
+    }
+}
+
+impl IMMNotificationClient_Impl for Watcher {
+    fn OnDeviceStateChanged(&self, _device: &PCWSTR, _state: u32) -> windows::core::Result<()> {
+        self.changed()
+    }
+
+    fn OnDeviceAdded(&self, _device: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceRemoved(&self, _device: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDefaultDeviceChanged(&self, flow: EDataFlow, role: ERole, _device: &PCWSTR) -> windows::core::Result<()> {
+        // sessions are listed from the default multimedia output
+        if flow == eRender && role == eMultimedia {
+            self.changed()?;
+        }
+        Ok(())
+    }
+
+    fn OnPropertyValueChanged(&self, _device: &PCWSTR, _key: &PROPERTYKEY) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+/// Registrations made by `subscribe`, undone when the backend is dropped.
+struct Subscription {
+    notification: IAudioSessionNotification,
+    events: IAudioSessionEvents,
+    client: IMMNotificationClient,
+    // session manager of the output the notification is registered with
+    manager: Option<(String, IAudioSessionManager2)>,
+    // sessions that report volume changes and disconnects, by instance id
+    watched: HashMap<String, IAudioSessionControl2>,
+}
+
+/// Per-application sessions on the default render endpoint, and the master
+/// volume of every active endpoint.
+///
+/// COM objects belong to the thread that created them, so a backend has to
+/// stay on the thread it was made on.
+pub struct WasapiBackend {
+    enumerator: IMMDeviceEnumerator,
+    // volume controls and meters from the last listing, keyed by session instance id
+    controls: RefCell<HashMap<String, ISimpleAudioVolume>>,
+    meters: RefCell<HashMap<String, IAudioMeterInformation>>,
+    // passed with every change this backend makes; other backends' changes
+    // carry their own and still mark this one's cache stale
+    own_change: GUID,
+    processes: RefCell<System>,
+    // executable name and path by pid, pids don't change owner while a session lives
+    names: RefCell<HashMap<u32, (String, Option<String>)>>,
+    subscription: RefCell<Option<Subscription>>,
+}
+
+impl WasapiBackend {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        unsafe {
+            // session notifications only arrive in the multithreaded apartment,
+            // fails harmlessly when the thread already joined one
+            let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+            let enumerator = Com::CoCreateInstance::<_, IMMDeviceEnumerator>(
+                &MMDeviceEnumerator,
+                None,
+                Com::CLSCTX_ALL,
+            )?;
+            Ok(Self {
+                enumerator,
+                controls: RefCell::new(HashMap::new()),
+                meters: RefCell::new(HashMap::new()),
+                own_change: GUID::new()?,
+                processes: RefCell::new(System::new()),
+                names: RefCell::new(HashMap::new()),
+                subscription: RefCell::new(None),
+            })
+        }
+    }
+
+    fn control(&self, session: &AudioSession) -> Result<ISimpleAudioVolume, anyhow::Error> {
+        if let Some(control) = self.controls.borrow().get(&session.id) {
+            return Ok(control.clone());
+        }
+        self.sessions()?;
+        self.controls.borrow().get(&session.id).cloned()
+            .ok_or_else(|| anyhow::Error::msg("Audio session is gone!"))
+    }
+
+    /// Name and path of a process, only asks the OS about pids it hasn't seen.
+    fn process_name(&self, pid: u32) -> Option<(String, Option<String>)> {
+        if let Some(name) = self.names.borrow().get(&pid) {
+            return Some(name.clone());
+        }
+        let mut processes = self.processes.borrow_mut();
+        if !processes.refresh_process(Pid::from_u32(pid)) {
+            return None;
+        }
+        let process = processes.process(Pid::from_u32(pid))?;
+        let name = (
+            process.name().to_string(),
+            Some(process.exe().to_string_lossy().to_string()).filter(|p| !p.is_empty()),
+        );
+        self.names.borrow_mut().insert(pid, name.clone());
+        Some(name)
+    }
+
+    /// Keeps the subscription's notifications pointed at the current default
+    /// output and its sessions.
+    unsafe fn watch(&self, speakers: &IMMDevice, manager: &IAudioSessionManager2, sessions: &HashMap<String, IAudioSessionControl2>) -> Result<(), anyhow::Error> {
+        let mut subscription = self.subscription.borrow_mut();
+        let subscription = match subscription.as_mut() {
+            Some(subscription) => subscription,
+            None => return Ok(()),
+        };
+        let speakers = device_id(speakers)?;
+        if subscription.manager.as_ref().map(|(id, _)| id) != Some(&speakers) {
+            if let Some((_, old)) = subscription.manager.take() {
+                let _ = old.UnregisterSessionNotification(&subscription.notification);
+            }
+            manager.RegisterSessionNotification(&subscription.notification)?;
+            subscription.manager = Some((speakers, manager.clone()));
+        }
+        subscription.watched.retain(|id, control| {
+            let keep = sessions.contains_key(id);
+            if !keep {
+                let _ = control.UnregisterAudioSessionNotification(&subscription.events);
+            }
+            keep
+        });
+        for (id, control) in sessions {
+            if !subscription.watched.contains_key(id) {
+                control.RegisterAudioSessionNotification(&subscription.events)?;
+                subscription.watched.insert(id.clone(), control.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn endpoint(&self, device: &AudioDevice) -> Result<IAudioEndpointVolume, anyhow::Error> {
+        unsafe {
+            let device = self.enumerator.GetDevice(&HSTRING::from(device.id.as_str()))?;
+            Ok(device.Activate(Com::CLSCTX_ALL, None)?)
+        }
+    }
+
+    fn endpoints(&self, flow: EDataFlow, kind: DeviceKind) -> Result<Vec<AudioDevice>, anyhow::Error> {
+        let mut devices = Vec::new();
+        unsafe {
+            let default = self.enumerator.GetDefaultAudioEndpoint(flow, eMultimedia).ok()
+                .and_then(|d| device_id(&d).ok());
+            let collection = self.enumerator.EnumAudioEndpoints(flow, DEVICE_STATE_ACTIVE)?;
+            for n in 0..collection.GetCount()? {
+                let device = collection.Item(n)?;
+                let id = device_id(&device)?;
+                let endpoint: IAudioEndpointVolume = device.Activate(Com::CLSCTX_ALL, None)?;
+                devices.push(AudioDevice {
+                    name: friendly_name(&device).unwrap_or_else(|_| id.clone()),
+                    is_default: default.as_deref() == Some(id.as_str()),
+                    id,
+                    kind,
+                    volume: endpoint.GetMasterVolumeLevelScalar()?,
+                    muted: endpoint.GetMute()?.as_bool(),
+                });
+            }
+        }
+        Ok(devices)
+    }
+}
+
+impl AudioBackend for WasapiBackend {
+    fn sessions(&self) -> Result<Vec<AudioSession>, anyhow::Error> {
+        let mut applications: Vec<AudioSession> = Vec::new();
+        let mut controls = HashMap::new();
+        let mut meters = HashMap::new();
+        let mut watched = HashMap::new();
+
+        unsafe {
+            let speakers = self.enumerator.GetDefaultAudioEndpoint(eRender, eMultimedia)?;
+            let manager: IAudioSessionManager2 = speakers.Activate(Com::CLSCTX_ALL, None)?;
+            let sessions = manager.GetSessionEnumerator()?;
+
+            for n in 0..sessions.GetCount()? {
+                let session_control = sessions.GetSession(n)?;
+                let ctl: IAudioSessionControl2 = session_control.cast()?;
+                let process_id = ctl.GetProcessId()?;
+
+                let simple_audio_ctl: ISimpleAudioVolume = ctl.cast()?;
+                let volume = simple_audio_ctl.GetMasterVolume()?;
+                let muted = simple_audio_ctl.GetMute()?.as_bool();
+                if let Some((name, path)) = self.process_name(process_id) {
+                    let instance = ctl.GetSessionInstanceIdentifier()?;
+                    let id = instance.to_string()?;
+                    CoTaskMemFree(Some(instance.0 as *const _));
+                    let raw_name = ctl.GetDisplayName()?;
+                    let display_name = raw_name.to_string().ok().filter(|n| !n.is_empty());
+                    CoTaskMemFree(Some(raw_name.0 as *const _));
+
+                    println!("Display Name: {}, Volume: {}%", name, volume * 100_f32);
+                    applications.push(AudioSession {
+                        id: id.clone(),
+                        name,
+                        pid: Some(process_id),
+                        path,
+                        display_name,
+                        volume,
+                        muted,
+                    });
+                    controls.insert(id.clone(), simple_audio_ctl);
+                    if let Ok(meter) = ctl.cast::<IAudioMeterInformation>() {
+                        meters.insert(id.clone(), meter);
+                    }
+                    watched.insert(id, ctl);
+                }
+            }
+
+            self.watch(&speakers, &manager, &watched)?;
+        }
+
+        // forget processes without a session, their pid may be reused
+        self.names.borrow_mut().retain(|pid, _| applications.iter().any(|a| a.pid == Some(*pid)));
+        *self.controls.borrow_mut() = controls;
+        *self.meters.borrow_mut() = meters;
+        Ok(applications)
+    }
+
+    fn set_volume(&self, session: &AudioSession, volume: f32) -> Result<(), anyhow::Error> {
+        let control = self.control(session)?;
+        unsafe { control.SetMasterVolume(volume, &self.own_change)?; }
+        Ok(())
+    }
+
+    fn set_mute(&self, session: &AudioSession, muted: bool) -> Result<(), anyhow::Error> {
+        let control = self.control(session)?;
+        unsafe { control.SetMute(muted, &self.own_change)?; }
+        Ok(())
+    }
+
+    fn devices(&self) -> Result<Vec<AudioDevice>, anyhow::Error> {
+        let mut devices = self.endpoints(eRender, DeviceKind::Output)?;
+        devices.extend(self.endpoints(eCapture, DeviceKind::Input)?);
+        Ok(devices)
+    }
+
+    fn set_device_volume(&self, device: &AudioDevice, volume: f32) -> Result<(), anyhow::Error> {
+        let endpoint = self.endpoint(device)?;
+        unsafe { endpoint.SetMasterVolumeLevelScalar(volume, std::ptr::null())?; }
+        Ok(())
+    }
+
+    fn set_device_mute(&self, device: &AudioDevice, muted: bool) -> Result<(), anyhow::Error> {
+        let endpoint = self.endpoint(device)?;
+        unsafe { endpoint.SetMute(muted, std::ptr::null())?; }
+        Ok(())
+    }
+
+    fn set_default_output(&self, device: &AudioDevice) -> Result<(), anyhow::Error> {
+        let id = HSTRING::from(device.id.as_str());
+        unsafe {
+            let policy: IPolicyConfig = Com::CoCreateInstance(&CPOLICY_CONFIG_CLIENT, None, Com::CLSCTX_ALL)?;
+            // the sound settings set all three roles, so do the same
+            for role in [eConsole, eMultimedia, eCommunications] {
+                policy.SetDefaultEndpoint(PCWSTR(id.as_ptr()), role).ok()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn playing(&self) -> Result<Vec<String>, anyhow::Error> {
+        let mut playing = Vec::new();
+        for (id, meter) in self.meters.borrow().iter() {
+            // sessions that ended since the listing just fail to read
+            if unsafe { meter.GetPeakValue() }.map_or(false, |peak| peak > PEAK_THRESHOLD) {
+                playing.push(id.clone());
+            }
+        }
+        Ok(playing)
+    }
+
+    fn subscribe(&self, stale: Arc<AtomicBool>) -> Result<(), anyhow::Error> {
+        let notification: IAudioSessionNotification = Watcher { stale, own_change: self.own_change }.into();
+        let subscription = Subscription {
+            events: notification.cast()?,
+            client: notification.cast()?,
+            notification,
+            manager: None,
+            watched: HashMap::new(),
+        };
+        unsafe { self.enumerator.RegisterEndpointNotificationCallback(&subscription.client)?; }
+        *self.subscription.borrow_mut() = Some(subscription);
+        // registers the session notifications, which only fire once sessions were enumerated
+        self.sessions()?;
+        Ok(())
+    }
+}
+
+impl Drop for WasapiBackend {
+    fn drop(&mut self) {
+        if let Some(subscription) = self.subscription.get_mut().take() {
+            unsafe {
+                let _ = self.enumerator.UnregisterEndpointNotificationCallback(&subscription.client);
+                if let Some((_, manager)) = &subscription.manager {
+                    let _ = manager.UnregisterSessionNotification(&subscription.notification);
+                }
+                for control in subscription.watched.values() {
+                    let _ = control.UnregisterAudioSessionNotification(&subscription.events);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use crate::error::HidError;
+use crate::packet::{header_of, Framing, Packet, PacketHeader, Reassembler};
+use crate::transport::{Transport, TransportInfo};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureDirection {
+    In,
+    Out,
+}
+
+/// One line of a capture file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureRecord {
+    pub timestamp_ms: u64,
+    pub direction: CaptureDirection,
+    pub header: String,
+    pub report: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, HidError> {
+    hex.split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).map_err(|e| HidError::new(e.to_string())))
+        .collect()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+/// Appends every report to a JSON lines file.
+pub struct Capture {
+    file: Mutex<File>,
+}
+
+impl Capture {
+    pub fn open(path: &str) -> Result<Self, HidError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, direction: CaptureDirection, report: &[u8]) {
+        let record = CaptureRecord {
+            timestamp_ms: now_ms(),
+            direction,
+            header: format!("{:?}", header_of(report)),
+            report: to_hex(report),
+        };
+        if let Ok(line) = serde_json::to_string(&record) {
+            let mut file = self.file.lock().unwrap();
+            if let Err(e) = writeln!(file, "{}", line) {
+                println!("Cannot write capture: {}", e);
+            }
+        }
+    }
+}
+
+pub fn load(path: &str) -> Result<Vec<CaptureRecord>, HidError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str::<CaptureRecord>(&line)?);
+    }
+    Ok(records)
+}
+
+/// Passes everything through to `inner`, recording it on the way.
+pub struct CapturingTransport<'a> {
+    inner: &'a dyn Transport,
+    capture: Capture,
+}
+
+impl<'a> CapturingTransport<'a> {
+    pub fn new(inner: &'a dyn Transport, capture: Capture) -> Self {
+        Self {
+            inner,
+            capture,
+        }
+    }
+}
+
+impl<'a> Transport for CapturingTransport<'a> {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        let read = self.inner.read_timeout(buf, timeout)?;
+        if read > 0 {
+            self.capture.record(CaptureDirection::In, &buf[..read]);
+        }
+        Ok(read)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        self.capture.record(CaptureDirection::Out, data);
+        self.inner.write(data)
+    }
+
+    fn close(&self) {
+        self.inner.close();
+    }
+
+    fn info(&self) -> TransportInfo {
+        self.inner.info()
+    }
+}
+
+/// Stats carry live readings from whichever machine recorded them and come
+/// on their own timer, so they're never compared.
+fn is_stats(report: &[u8]) -> bool {
+    matches!(header_of(report), PacketHeader::Stats | PacketHeader::Temperature)
+}
+
+// how long the host gets to finish writing once the capture has run out
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A write with its framing and sealing stripped.
+struct Written {
+    /// Sequence number the device has to ack, reliable mode only.
+    sequence: Option<u8>,
+    /// `None` for writes that are never compared.
+    compared: Option<Vec<u8>>,
+}
+
+/// Turns written reports back into what was meant to be sent.
+///
+/// Sealed packets carry sequence numbers that depend on how many stats went
+/// out before them, so in reliable mode whole packets are compared without
+/// their seal, and acks and retransmissions are left out.
+struct WriteDecoder {
+    reliable: bool,
+    reassembler: Reassembler,
+    last_sequence: Option<u8>,
+}
+
+impl WriteDecoder {
+    fn new(reliable: bool) -> Self {
+        Self {
+            reliable,
+            reassembler: Reassembler::new(Framing::Fragmented),
+            last_sequence: None,
+        }
+    }
+
+    /// `None` until the last report of a packet, and for acks.
+    fn push(&mut self, report: &[u8]) -> Option<Written> {
+        // the hello is legacy framed and never sealed
+        if !self.reliable || header_of(report) == PacketHeader::Hello {
+            let compared = (!is_stats(report)).then(|| report.to_vec());
+            return Some(Written { sequence: None, compared });
+        }
+
+        let packet = match self.reassembler.push(report) {
+            Ok(Some(p)) => p,
+            Ok(None) => return None,
+            Err(e) => {
+                println!("Bad write: {}", e.message);
+                return Some(Written { sequence: None, compared: Some(report.to_vec()) });
+            }
+        };
+        if packet.header == PacketHeader::Ack || packet.header == PacketHeader::Nack {
+            return None;
+        }
+        let (sequence, packet) = match packet.unseal() {
+            Ok(unsealed) => unsealed,
+            Err(e) => {
+                println!("Bad write: {}", e.message);
+                return Some(Written { sequence: None, compared: Some(report.to_vec()) });
+            }
+        };
+
+        let retransmitted = self.last_sequence == Some(sequence);
+        self.last_sequence = Some(sequence);
+        let compared = if retransmitted || matches!(packet.header, PacketHeader::Stats | PacketHeader::Temperature) {
+            None
+        } else {
+            let mut bytes = vec![packet.header.into_u8()];
+            bytes.extend_from_slice(&packet.raw());
+            Some(bytes)
+        };
+        Some(Written { sequence: Some(sequence), compared })
+    }
+}
+
+struct ReplayState {
+    started: Instant,
+    first_timestamp: u64,
+    inbound: VecDeque<(u64, Vec<u8>)>,
+    outbound: VecDeque<Vec<u8>>,
+    /// Acks for our sealed writes, read before anything else.
+    acks: VecDeque<Vec<u8>>,
+    writes: WriteDecoder,
+    finished: Option<Instant>,
+    mismatches: usize,
+}
+
+/// Fake device that plays the inbound half of a capture back with its
+/// original timing and compares what we write against the outbound half.
+///
+/// Captures with acks in them were recorded in reliable mode. The recorded
+/// acks answer the recorded sequence numbers, so they're dropped and every
+/// sealed write gets a fresh ack instead.
+///
+/// Reads fail once the capture runs out, which ends the device loop the same
+/// way unplugging the pad would.
+pub struct ReplayDevice {
+    reliable: bool,
+    state: Mutex<ReplayState>,
+    acked: Condvar,
+}
+
+impl ReplayDevice {
+    pub fn new(records: Vec<CaptureRecord>) -> Result<Self, HidError> {
+        let first_timestamp = records.first().map(|r| r.timestamp_ms).unwrap_or_default();
+        let mut reports = Vec::new();
+        for record in records {
+            reports.push((record.timestamp_ms, record.direction, from_hex(&record.report)?));
+        }
+        let reliable = reports.iter()
+            .any(|(_, _, report)| matches!(header_of(report), PacketHeader::Ack | PacketHeader::Nack));
+
+        let mut inbound = VecDeque::new();
+        let mut outbound = VecDeque::new();
+        let mut expected = WriteDecoder::new(reliable);
+        for (timestamp, direction, report) in reports {
+            match direction {
+                CaptureDirection::In if reliable && matches!(header_of(&report), PacketHeader::Ack | PacketHeader::Nack) => (),
+                CaptureDirection::In => inbound.push_back((timestamp, report)),
+                CaptureDirection::Out => {
+                    if let Some(compared) = expected.push(&report).and_then(|w| w.compared) {
+                        outbound.push_back(compared);
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            reliable,
+            state: Mutex::new(ReplayState {
+                started: Instant::now(),
+                first_timestamp,
+                inbound,
+                outbound,
+                acks: VecDeque::new(),
+                writes: WriteDecoder::new(reliable),
+                finished: None,
+                mismatches: 0,
+            }),
+            acked: Condvar::new(),
+        })
+    }
+
+    /// Whether the capture was recorded with reliable delivery on.
+    pub fn reliable(&self) -> bool {
+        self.reliable
+    }
+
+    /// Writes that differed from the capture, and recorded ones that never came.
+    pub fn mismatches(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.mismatches + state.outbound.len()
+    }
+}
+
+fn copy_report(buf: &mut [u8], report: &[u8]) -> usize {
+    let len = report.len().min(buf.len());
+    buf[..len].copy_from_slice(&report[..len]);
+    len
+}
+
+impl Transport for ReplayDevice {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        let deadline = (timeout >= 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(ack) = state.acks.pop_front() {
+                return Ok(copy_report(buf, &ack));
+            }
+
+            let wait = match state.inbound.front() {
+                Some((timestamp, _)) => {
+                    let due = Duration::from_millis(timestamp.saturating_sub(state.first_timestamp));
+                    due.saturating_sub(state.started.elapsed())
+                }
+                // give the host a moment to answer the last report
+                None if !state.outbound.is_empty() => {
+                    let finished = *state.finished.get_or_insert_with(Instant::now);
+                    match DRAIN_TIMEOUT.checked_sub(finished.elapsed()) {
+                        Some(remaining) if !remaining.is_zero() => remaining,
+                        _ => return Err(HidError::new("Replay finished.".to_string())),
+                    }
+                }
+                None => return Err(HidError::new("Replay finished.".to_string())),
+            };
+            if wait.is_zero() {
+                if let Some((_, report)) = state.inbound.pop_front() {
+                    return Ok(copy_report(buf, &report));
+                }
+            }
+
+            let wait = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(0);
+                    }
+                    wait.min(left)
+                }
+                None => wait,
+            };
+            state = self.acked.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        let mut state = self.state.lock().unwrap();
+        let written = match state.writes.push(data) {
+            Some(written) => written,
+            None => return Ok(data.len()),
+        };
+        if let Some(sequence) = written.sequence {
+            let ack = Packet::ack(sequence).to_reports(Framing::Fragmented)?.remove(0);
+            state.acks.push_back(ack);
+            self.acked.notify_all();
+        }
+        let compared = match written.compared {
+            Some(compared) => compared,
+            None => return Ok(data.len()),
+        };
+        match state.outbound.pop_front() {
+            Some(expected) if expected == compared => (),
+            Some(expected) => {
+                state.mismatches += 1;
+                println!("Replay mismatch:\n  expected {}\n  got      {}", to_hex(&expected), to_hex(&compared));
+            }
+            None => println!("Unexpected write: {}", to_hex(&compared)),
+        }
+        Ok(data.len())
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.inbound.clear();
+        state.acks.clear();
+        self.acked.notify_all();
+    }
+
+    fn info(&self) -> TransportInfo {
+        TransportInfo {
+            path: "replay".to_string(),
+            vendor_id: 0,
+            product_id: 0,
+            serial_number: None,
+            product: Some("Replay".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Encode, HostMessage, REPORT_SIZE};
+
+    fn report(message: HostMessage) -> Vec<u8> {
+        message.encode().to_reports(Framing::Fragmented).unwrap().remove(0)
+    }
+
+    fn record(direction: CaptureDirection, report: &[u8]) -> CaptureRecord {
+        CaptureRecord {
+            timestamp_ms: 0,
+            direction,
+            header: format!("{:?}", header_of(report)),
+            report: to_hex(report),
+        }
+    }
+
+    #[test]
+    fn replay_ignores_where_stats_fell() {
+        let stats = |cpu| report(HostMessage::Stats { cpu, mem: 50, procs: 300, gpu: 0 });
+        let volume = report(HostMessage::Volume { volume: Some(40), name: "spotify".to_string() });
+        let output = report(HostMessage::OutputDevice { name: "Speakers".to_string() });
+        let device = ReplayDevice::new(vec![
+            record(CaptureDirection::Out, &stats(10)),
+            record(CaptureDirection::Out, &volume),
+            record(CaptureDirection::Out, &stats(20)),
+            record(CaptureDirection::Out, &output),
+        ]).unwrap();
+
+        // fewer stats this time, and in other places
+        device.write(&volume).unwrap();
+        device.write(&stats(99)).unwrap();
+        device.write(&stats(98)).unwrap();
+        device.write(&output).unwrap();
+        assert_eq!(device.mismatches(), 0);
+
+        device.write(&volume).unwrap();
+        assert_eq!(device.mismatches(), 0, "writes past the capture aren't mismatches");
+    }
+
+    #[test]
+    fn replay_counts_mismatches() {
+        let device = ReplayDevice::new(vec![
+            record(CaptureDirection::Out, &report(HostMessage::Volume { volume: Some(40), name: "spotify".to_string() })),
+        ]).unwrap();
+        device.write(&report(HostMessage::Volume { volume: Some(45), name: "spotify".to_string() })).unwrap();
+        assert_eq!(device.mismatches(), 1);
+    }
+
+    #[test]
+    fn reliable_replay_acks_writes_under_their_own_sequence() {
+        let volume = HostMessage::Volume { volume: Some(40), name: "spotify".to_string() }.encode();
+        let sealed = |sequence| volume.seal(sequence).to_reports(Framing::Fragmented).unwrap().remove(0);
+        let ack = |sequence| Packet::ack(sequence).to_reports(Framing::Fragmented).unwrap().remove(0);
+        let device = ReplayDevice::new(vec![
+            record(CaptureDirection::Out, &sealed(7)),
+            record(CaptureDirection::In, &ack(7)),
+        ]).unwrap();
+        assert!(device.reliable());
+
+        device.write(&sealed(0)).unwrap();
+        assert_eq!(device.mismatches(), 0);
+        let mut buf = [0u8; REPORT_SIZE];
+        let len = device.read_timeout(&mut buf, 0).unwrap();
+        assert_eq!(buf[..len], ack(0)[..]);
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::packet::FirmwareInfo;
+
+const ERROR_HISTORY: usize = 10;
+
+pub type Devices = Arc<RwLock<HashMap<String, DeviceStatus>>>;
+
+/// Where a device is in its life, as shown to the user.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state")]
+pub enum ConnectionState {
+    /// Seen before but not plugged in any more.
+    NoDevice,
+    Opening,
+    /// The OS refused to open the node, usually a missing udev rule.
+    PermissionDenied { path: String },
+    Connected { firmware: FirmwareInfo, serial: Option<String> },
+    /// The last attempt failed, `since` is in milliseconds since the epoch.
+    Errored { reason: String, since: u64 },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorRecord {
+    pub timestamp_ms: u64,
+    pub reason: String,
+}
+
+/// What the UI shows for every device seen since startup.
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceStatus {
+    pub id: String,
+    pub product: Option<String>,
+    pub state: ConnectionState,
+    /// Most recent last, capped at `ERROR_HISTORY` entries.
+    pub errors: VecDeque<ErrorRecord>,
+}
+
+impl DeviceStatus {
+    /// A worker owns the device, so nobody else should open it.
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, ConnectionState::Opening | ConnectionState::Connected { .. })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+pub fn is_active(devices: &Devices, id: &str) -> bool {
+    devices.read().unwrap().get(id).map_or(false, |s| s.is_active())
+}
+
+/// Moves a device to `state`, recording failures in its error history.
+pub fn set_state(devices: &Devices, id: &str, product: Option<String>, state: ConnectionState) {
+    let mut devices = devices.write().unwrap();
+    let status = devices.entry(id.to_string()).or_insert_with(|| DeviceStatus {
+        id: id.to_string(),
+        product: None,
+        state: ConnectionState::NoDevice,
+        errors: VecDeque::new(),
+    });
+    if product.is_some() {
+        status.product = product;
+    }
+    let reason = match &state {
+        ConnectionState::Errored { reason, .. } => Some(reason.clone()),
+        ConnectionState::PermissionDenied { path } => Some(format!("Permission denied opening {}", path)),
+        _ => None,
+    };
+    if let Some(reason) = reason {
+        if status.errors.len() == ERROR_HISTORY {
+            status.errors.pop_front();
+        }
+        status.errors.push_back(ErrorRecord {
+            timestamp_ms: now_ms(),
+            reason,
+        });
+    }
+    status.state = state;
+}
+
+pub fn set_errored(devices: &Devices, id: &str, reason: String) {
+    set_state(devices, id, None, ConnectionState::Errored { reason, since: now_ms() });
+}
+
+/// Marks every known device that isn't in `present` and has no worker as gone.
+pub fn mark_missing(devices: &Devices, present: &[String]) {
+    let mut devices = devices.write().unwrap();
+    for status in devices.values_mut() {
+        if !status.is_active() && !present.contains(&status.id) {
+            status.state = ConnectionState::NoDevice;
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Serialize;
+use crate::error::HidError;
+use crate::hid::{DeviceMatch, HidDeviceEntry};
+
+pub const RULE_PATH: &str = "/etc/udev/rules.d/70-macropad.rules";
+
+/// Whether opening `path` fails because of its permissions, which hidapi and
+/// serialport don't tell us themselves.
+#[cfg(unix)]
+pub fn permission_denied(path: &str) -> bool {
+    match std::fs::OpenOptions::new().read(true).write(true).open(path) {
+        Err(e) => e.kind() == std::io::ErrorKind::PermissionDenied,
+        Ok(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn permission_denied(_path: &str) -> bool {
+    false
+}
+
+/// Rule giving the logged in user access to the hidraw and serial nodes of
+/// the configured device.
+pub fn udev_rule(rule: &DeviceMatch) -> String {
+    let ids = format!("ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\"", rule.vendor_id, rule.product_id);
+    format!(
+        "# Macropad, lets the desktop user open the device without root\n\
+         SUBSYSTEM==\"hidraw\", {ids}, MODE=\"0660\", TAG+=\"uaccess\"\n\
+         SUBSYSTEM==\"tty\", {ids}, MODE=\"0660\", TAG+=\"uaccess\"\n",
+        ids = ids
+    )
+}
+
+pub fn explain(path: &str) -> String {
+    format!(
+        "{} exists but this user may not open it. On Linux hidraw nodes belong to root \
+         unless a udev rule says otherwise; install the rule below to {} and replug the macropad.",
+        path, RULE_PATH
+    )
+}
+
+/// A device node matching the configured rule.
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeAccess {
+    pub path: String,
+    pub permission_denied: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Diagnosis {
+    pub nodes: Vec<NodeAccess>,
+    /// Set when at least one node can't be opened.
+    pub problem: Option<String>,
+    pub rule: String,
+    pub rule_path: String,
+    pub rule_installed: bool,
+    /// Whether `install_rule` can work here.
+    pub can_install: bool,
+}
+
+/// `devices` is the HID thread's last enumeration, see `hid::HidDevices`.
+pub fn diagnose(rule: &DeviceMatch, devices: &[HidDeviceEntry]) -> Diagnosis {
+    let nodes: Vec<NodeAccess> = devices.iter()
+        .filter(|d| rule.matches(d))
+        .map(|d| NodeAccess {
+            permission_denied: permission_denied(&d.path),
+            path: d.path.clone(),
+        })
+        .collect();
+    let problem = nodes.iter().find(|n| n.permission_denied).map(|n| explain(&n.path));
+    let expected = udev_rule(rule);
+    let rule_installed = std::fs::read_to_string(RULE_PATH).map_or(false, |installed| installed == expected);
+    Diagnosis {
+        nodes,
+        problem,
+        rule: expected,
+        rule_path: RULE_PATH.to_string(),
+        rule_installed,
+        can_install: cfg!(target_os = "linux") && which("pkexec"),
+    }
+}
+
+fn which(program: &str) -> bool {
+    std::env::var_os("PATH").map_or(false, |paths| {
+        std::env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+    })
+}
+
+/// Writes the rule through pkexec, which asks for the admin password, then
+/// makes udev apply it to devices that are already plugged in.
+///
+/// The rule goes over stdin; a staging file in the shared temp dir could be
+/// swapped by another user before root copies it.
+#[cfg(target_os = "linux")]
+pub fn install_rule(rule: &DeviceMatch) -> Result<(), HidError> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("pkexec")
+        .arg("sh")
+        .arg("-c")
+        .arg("umask 022 && cat > \"$1\" && udevadm control --reload-rules && udevadm trigger --subsystem-match=hidraw --subsystem-match=tty")
+        .arg("sh")
+        .arg(RULE_PATH)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    // dropping stdin closes it, which ends cat; a cancelled prompt closes the
+    // pipe early, so wait for the status before reporting a write error
+    let written = child.stdin.take()
+        .map_or(Ok(()), |mut stdin| stdin.write_all(udev_rule(rule).as_bytes()));
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(HidError::new(format!("Installing the udev rule failed ({}), install it by hand:\n{}", status, udev_rule(rule))));
+    }
+    written?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn install_rule(_rule: &DeviceMatch) -> Result<(), HidError> {
+    Err(HidError::new("udev rules are only needed on Linux.".to_string()))
+}
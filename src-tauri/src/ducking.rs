@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use crate::Settings;
+use crate::audio::{self, AudioBackend, Control};
+use crate::mapping::Target;
+use crate::volume::Level;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// a ducked control further than this from where the rule put it was moved by the user
+const TOLERANCE: f32 = 0.005;
+
+/// While any of `triggers` makes a sound, `targets` are turned down to
+/// `level`, like music under a call.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DuckingRule {
+    pub triggers: Vec<Target>,
+    pub targets: Vec<Target>,
+    /// Between 0.0 and 1.0, targets that are already quieter stay as they are.
+    pub level: f32,
+    /// How long the triggers have to be quiet before the targets come back
+    /// up, so pauses in speech don't pump the music.
+    #[serde(default = "default_release_ms")]
+    pub release_ms: u64,
+}
+
+fn default_release_ms() -> u64 {
+    2000
+}
+
+/// A control a rule turned down.
+struct Ducked {
+    id: String,
+    /// Where the user had it, put back afterwards.
+    restore: f32,
+    /// Where the rule put it.
+    ducked: f32,
+}
+
+/// A rule whose triggers are playing, or were until less than `release_ms` ago.
+struct Active {
+    rule: DuckingRule,
+    ducked: Vec<Ducked>,
+    quiet_since: Option<Instant>,
+}
+
+/// Starts the thread that applies `Settings.ducking`.
+pub fn spawn_monitor(settings: Arc<RwLock<Settings>>) {
+    std::thread::spawn(move || {
+        let audio = match audio::default_backend() {
+            Ok(audio) => audio,
+            Err(e) => {
+                println!("No audio backend, ducking is off: {}", e);
+                return;
+            }
+        };
+        let mut active: Vec<Active> = Vec::new();
+        loop {
+            sleep(POLL_INTERVAL);
+            let rules = settings.read().unwrap().ducking.clone();
+            if let Err(e) = poll(&*audio, &rules, &mut active) {
+                println!("Cannot apply ducking rules: {}", e);
+            }
+        }
+    });
+}
+
+fn resolve_all(audio: &dyn AudioBackend, targets: &[Target]) -> Result<Vec<Control>, anyhow::Error> {
+    let mut controls: Vec<Control> = Vec::new();
+    for target in targets {
+        for control in audio::resolve(audio, target)? {
+            if !controls.iter().any(|c| c.id() == control.id()) {
+                controls.push(control);
+            }
+        }
+    }
+    Ok(controls)
+}
+
+fn poll(audio: &dyn AudioBackend, rules: &[DuckingRule], active: &mut Vec<Active>) -> Result<(), anyhow::Error> {
+    // rules that were edited or deleted give their targets back right away
+    let (kept, dropped): (Vec<Active>, Vec<Active>) = active.drain(..).partition(|a| rules.contains(&a.rule));
+    *active = kept;
+    for released in dropped {
+        if let Err(e) = release(audio, &released) {
+            println!("Cannot restore after ducking: {}", e);
+        }
+    }
+    if rules.is_empty() {
+        return Ok(());
+    }
+
+    // lists sessions first so the playing check covers the ones that just started
+    audio.sessions()?;
+    let playing: HashSet<String> = audio.playing()?.into_iter().collect();
+    for rule in rules {
+        let triggered = resolve_all(audio, &rule.triggers)?.iter()
+            .any(|c| matches!(c, Control::Session(_)) && playing.contains(c.id()));
+        match (triggered, active.iter().position(|a| a.rule == *rule)) {
+            (true, None) => {
+                println!("Ducking {:?}", rule.targets);
+                let mut ducking = Active { rule: rule.clone(), ducked: Vec::new(), quiet_since: None };
+                duck(audio, &mut ducking)?;
+                active.push(ducking);
+            }
+            (true, Some(i)) => {
+                active[i].quiet_since = None;
+                // catches targets that started playing during the duck
+                duck(audio, &mut active[i])?;
+            }
+            (false, Some(i)) => {
+                let quiet_since = *active[i].quiet_since.get_or_insert_with(Instant::now);
+                if quiet_since.elapsed() >= Duration::from_millis(rule.release_ms) {
+                    println!("Restoring {:?}", rule.targets);
+                    release(audio, &active.remove(i))?;
+                }
+            }
+            (false, None) => (),
+        }
+    }
+    Ok(())
+}
+
+fn duck(audio: &dyn AudioBackend, active: &mut Active) -> Result<(), anyhow::Error> {
+    for control in resolve_all(audio, &active.rule.targets)? {
+        if active.ducked.iter().any(|d| d.id == control.id()) {
+            continue;
+        }
+        let restore = control.volume();
+        let ducked = restore.min(active.rule.level);
+        control.apply(audio, Level { volume: ducked, muted: control.muted() })?;
+        active.ducked.push(Ducked { id: control.id().to_string(), restore, ducked });
+    }
+    Ok(())
+}
+
+/// Puts ducked controls back where the user had them, unless the user moved
+/// them in the meantime, then their new level stays.
+fn release(audio: &dyn AudioBackend, active: &Active) -> Result<(), anyhow::Error> {
+    let controls = resolve_all(audio, &active.rule.targets)?;
+    for ducked in &active.ducked {
+        if let Some(control) = controls.iter().find(|c| c.id() == ducked.id) {
+            if (control.volume() - ducked.ducked).abs() < TOLERANCE {
+                control.apply(audio, Level { volume: ducked.restore, muted: control.muted() })?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -42,4 +42,10 @@ impl From<NvmlError> for HidError {
     fn from(value: NvmlError) -> Self {
         HidError::new(value.to_string())
     }
-}
\ No newline at end of file
+}
+
+impl From<serialport::Error> for HidError {
+    fn from(value: serialport::Error) -> Self {
+        HidError::new(value.to_string())
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+
+// holding a key repeats it many times a second, focus doesn't move that fast
+const FOCUS_CACHE: Duration = Duration::from_millis(500);
+// deeper than any real process tree, guards against parent loops
+const MAX_DEPTH: usize = 64;
+
+static FOCUSED: Mutex<Option<(Instant, u32)>> = Mutex::new(None);
+
+/// Process owning the focused window.
+#[cfg(windows)]
+fn foreground_pid() -> Result<u32, anyhow::Error> {
+    use windows::Win32::UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId};
+
+    let mut pid = 0u32;
+    unsafe {
+        let window = GetForegroundWindow();
+        if window.0 == 0 {
+            return Err(anyhow::Error::msg("No window has focus!"));
+        }
+        GetWindowThreadProcessId(window, Some(&mut pid as *mut u32));
+    }
+    Ok(pid)
+}
+
+/// Process owning the focused window.
+///
+/// Wayland has no common way to ask for this, so Hyprland and Sway are asked
+/// through their own IPC. Other Wayland compositors aren't supported, X11
+/// would only see XWayland windows there and report the wrong one.
+#[cfg(target_os = "linux")]
+fn foreground_pid() -> Result<u32, anyhow::Error> {
+    if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        let window: serde_json::Value = serde_json::from_str(&run("hyprctl", &["activewindow", "-j"])?)?;
+        return window["pid"].as_u64().map(|p| p as u32)
+            .ok_or_else(|| anyhow::Error::msg("Hyprland reports no active window!"));
+    }
+    if std::env::var_os("SWAYSOCK").is_some() {
+        let tree: serde_json::Value = serde_json::from_str(&run("swaymsg", &["-t", "get_tree"])?)?;
+        return focused_sway_pid(&tree).ok_or_else(|| anyhow::Error::msg("Sway reports no focused window!"));
+    }
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        return Err(anyhow::Error::msg("Finding the focused window is only supported on Hyprland and Sway under Wayland!"));
+    }
+
+    // _NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007
+    let active = run("xprop", &["-root", "_NET_ACTIVE_WINDOW"])?;
+    let window = active.rsplit(' ').next().map(|w| w.trim().to_string())
+        .filter(|w| w.starts_with("0x") && w != "0x0")
+        .ok_or_else(|| anyhow::Error::msg("No window has focus!"))?;
+    // _NET_WM_PID(CARDINAL) = 4242
+    let pid = run("xprop", &["-id", &window, "_NET_WM_PID"])?;
+    pid.rsplit(' ').next().and_then(|p| p.trim().parse().ok())
+        .ok_or_else(|| anyhow::Error::msg("Focused window doesn't report its process!"))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn foreground_pid() -> Result<u32, anyhow::Error> {
+    Err(anyhow::Error::msg("Finding the focused window isn't supported on this platform!"))
+}
+
+#[cfg(target_os = "linux")]
+fn focused_sway_pid(node: &serde_json::Value) -> Option<u32> {
+    if node["focused"].as_bool() == Some(true) {
+        if let Some(pid) = node["pid"].as_u64() {
+            return Some(pid as u32);
+        }
+    }
+    ["nodes", "floating_nodes"].iter()
+        .filter_map(|key| node[*key].as_array())
+        .flatten()
+        .find_map(focused_sway_pid)
+}
+
+#[cfg(target_os = "linux")]
+fn run(program: &str, args: &[&str]) -> Result<String, anyhow::Error> {
+    let output = std::process::Command::new(program).args(args).output().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => anyhow::Error::msg(format!("{} is not installed!", program)),
+        _ => e.into(),
+    })?;
+    if !output.status.success() {
+        return Err(anyhow::Error::msg(format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// `foreground_pid`, reused for `FOCUS_CACHE` so key repeat doesn't ask the
+/// window system again on every press.
+fn cached_foreground_pid() -> Result<u32, anyhow::Error> {
+    let mut focused = FOCUSED.lock().unwrap();
+    if let Some((at, pid)) = *focused {
+        if at.elapsed() < FOCUS_CACHE {
+            return Ok(pid);
+        }
+    }
+    let pid = foreground_pid()?;
+    *focused = Some((Instant::now(), pid));
+    Ok(pid)
+}
+
+/// The focused process and all of its descendants. Browsers and Electron apps
+/// play audio from a child process rather than the one owning the window.
+pub struct Foreground {
+    root: Pid,
+    sys: System,
+}
+
+impl Foreground {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            root: Pid::from_u32(cached_foreground_pid()?),
+            sys: System::new(),
+        })
+    }
+
+    /// Walks up from `pid` looking for the focused process, so only its
+    /// ancestors are read rather than every process on the system.
+    pub fn contains(&mut self, pid: u32) -> bool {
+        let mut pid = Pid::from_u32(pid);
+        for _ in 0..MAX_DEPTH {
+            if pid == self.root {
+                return true;
+            }
+            if !self.sys.refresh_process(pid) {
+                return false;
+            }
+            match self.sys.process(pid).and_then(|p| p.parent()) {
+                Some(parent) if parent != pid => pid = parent,
+                _ => return false,
+            }
+        }
+        false
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_descendants_only() {
+        let mut child = std::process::Command::new("sleep").arg("5").spawn().unwrap();
+        let root = Pid::from_u32(std::process::id());
+        let mut foreground = Foreground { root, sys: System::new() };
+
+        assert!(foreground.contains(std::process::id()));
+        assert!(foreground.contains(child.id()));
+        if let Some(parent) = foreground.sys.process(root).and_then(|p| p.parent()) {
+            assert!(!foreground.contains(parent.as_u32()));
+        }
+        assert!(!foreground.contains(u32::MAX - 1));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -1,180 +1,533 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
 use std::thread::sleep;
-use std::time::{Duration, Instant};
-use hidapi::{DeviceInfo, HidApi, HidDevice};
+use std::time::Duration;
+use hidapi::{DeviceInfo, HidApi};
+use serde::{Serialize, Deserialize};
 use nvml_wrapper::{Device, Nvml};
 use sysinfo::{CpuExt, System, SystemExt};
-use windows::Win32::System::Com::CoInitialize;
-use crate::{audio, Settings};
-use crate::audio::AudioEndpoint;
+use crate::{audio, scenes, temperature, volume, Settings};
+use crate::audio::AudioBackend;
+use crate::audio::mock::MockBackend;
+use crate::capture::{self, Capture, CapturingTransport, ReplayDevice};
+use crate::connection::{self, ConnectionState, Devices};
+use crate::diagnostics;
 use crate::error::HidError;
-use crate::packet::{Packet, PacketHeader};
+use crate::hotplug::{self, HotplugEvent};
+use crate::mapping::{GroupMode, Target, TypedTarget};
+use crate::link::{self, recv_packet, send_packet, LinkConfig, OUTBOUND_QUEUE};
+use crate::serial::{self, SerialTransport};
+use crate::transport::{HidTransport, Transport};
+use crate::packet::{Capabilities, Decode, DeviceMessage, Direction, Encode, FirmwareInfo, Framing, HostMessage, Packet, Reassembler, PROTOCOL_VERSION};
 
-#[repr(u8)]
-#[derive(Debug, PartialEq)]
-enum Volume {
-    Up = 0x01,
-    Down = 0x00,
+const HANDSHAKE_ATTEMPTS: usize = 3;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+// lets udev finish applying permissions to a new node before we open it
+const SETTLE_DELAY: Duration = Duration::from_millis(250);
+// keeps a device that keeps failing its handshake from being reopened in a tight loop
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Which HID interface we talk to. Unset optional fields match anything.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceMatch {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    #[serde(default)]
+    pub interface_number: Option<i32>,
+    #[serde(default)]
+    pub usage_page: Option<u16>,
+    #[serde(default)]
+    pub usage: Option<u16>,
+    #[serde(default)]
+    pub serial_number: Option<String>,
+    #[serde(default)]
+    pub product: Option<String>,
 }
 
-fn get_volume(application: String) -> Result<f32, anyhow::Error> {
-    println!("Getting volume for: {:?}", application);
-    let applications = audio::enumerate_applications()?;
-    let process = applications.iter().filter(|p| p.name.to_lowercase() == application).nth(0);
-    if let Some(process) = process {
-        let existing_volume = unsafe{process.volume.GetMasterVolume()?};
-        println!("Got volume: {}", existing_volume);
-        return Ok(existing_volume);
+impl Default for DeviceMatch {
+    fn default() -> Self {
+        Self {
+            vendor_id: 0xdeaf,
+            product_id: 0x0913,
+            interface_number: Some(1),
+            usage_page: None,
+            usage: None,
+            serial_number: None,
+            product: None,
+        }
     }
-    Err(anyhow::Error::msg("Cannot find running application!"))
 }
 
-fn change_volume(settings: Arc<RwLock<Settings>>, application: String, volume: Volume, force: bool) -> Result<(), anyhow::Error> {
-    let applications = audio::enumerate_applications()?;
-    let processes = applications.iter().filter(|p| p.name.to_lowercase() == application).collect::<Vec<&AudioEndpoint>>();
-    let set = settings.read().unwrap();
-    for process in processes {
-        let mut existing_volume = unsafe{process.volume.GetMasterVolume()?};
-        println!("Volume of {}: {}", application, existing_volume);
-        existing_volume = match &volume {
-            x if x == &Volume::Up && force => 1.0,
-            x if x == &Volume::Down && force => 0.0,
-            Volume::Up => existing_volume + set.increment as f32 * 0.01,
-            Volume::Down => existing_volume - set.increment as f32 * 0.01,
-            _ => 0.00
-        };
-        if existing_volume > 1.0 {
-            existing_volume = 1.0;
-        } else if existing_volume < set.increment as f32 * 0.01 {
-            existing_volume = 0.0;
+impl DeviceMatch {
+    pub fn matches(&self, device: &HidDeviceEntry) -> bool {
+        device.vendor_id == self.vendor_id
+            && device.product_id == self.product_id
+            && self.interface_number.map_or(true, |i| device.interface_number == i)
+            && self.usage_page.map_or(true, |p| device.usage_page == p)
+            && self.usage.map_or(true, |u| device.usage == u)
+            && self.serial_number.as_ref().map_or(true, |s| device.serial_number.as_ref() == Some(s))
+            && self.product.as_ref().map_or(true, |p| device.product.as_ref() == Some(p))
+    }
+}
+
+/// An enumerated HID interface, as shown in the UI.
+#[derive(Serialize, Debug, Clone)]
+pub struct HidDeviceEntry {
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub interface_number: i32,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub matched: bool,
+}
+
+/// Every HID interface from the HID thread's last enumeration, the UI can't
+/// enumerate itself since hidapi allows only one context.
+pub type HidDevices = Arc<RwLock<Vec<HidDeviceEntry>>>;
+
+impl From<&DeviceInfo> for HidDeviceEntry {
+    fn from(device: &DeviceInfo) -> Self {
+        HidDeviceEntry {
+            path: device.path().to_string_lossy().to_string(),
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            interface_number: device.interface_number(),
+            usage_page: device.usage_page(),
+            usage: device.usage(),
+            serial_number: device.serial_number().map(|s| s.to_string()),
+            manufacturer: device.manufacturer_string().map(|s| s.to_string()),
+            product: device.product_string().map(|s| s.to_string()),
+            matched: false,
         }
-        println!("Setting volume of {} to {}", application, existing_volume);
-        unsafe { process.volume.SetMasterVolume(existing_volume, std::ptr::null()); }
+    }
+}
+
+/// Every enumerated interface, `matched` against `rule`.
+pub fn mark_matched(mut devices: Vec<HidDeviceEntry>, rule: &DeviceMatch) -> Vec<HidDeviceEntry> {
+    for device in devices.iter_mut() {
+        device.matched = rule.matches(device);
+    }
+    devices
+}
+
+/// Groups and applications with several sessions report their loudest one.
+fn get_volume(audio: &dyn AudioBackend, target: &Target) -> Result<f32, anyhow::Error> {
+    println!("Getting volume for: {:?}", target);
+    if let Some(volume) = audio::loudest(&audio::resolve(audio, target)?) {
+        println!("Got volume: {}", volume);
+        return Ok(volume);
+    }
+    Err(anyhow::Error::msg("Cannot find running application!"))
+}
+
+fn change_volume(audio: &dyn AudioBackend, settings: &Arc<RwLock<Settings>>, id: &str, key: u16, target: &Target, volume: Direction, force: bool) -> Result<(), anyhow::Error> {
+    let controls = audio::resolve(audio, target)?;
+    let (options, increment) = {
+        let settings = settings.read().unwrap();
+        (settings.profile(id).volume.get(&key).cloned().unwrap_or_default(), settings.increment)
+    };
+    let normalized = match target {
+        Target::Typed(TypedTarget::Group { mode: GroupMode::Normalized, .. }) => audio::loudest(&controls),
+        _ => None,
+    };
+    for control in controls {
+        println!("Volume of {}: {}", control.name(), control.volume());
+        let current = normalized.unwrap_or_else(|| control.level());
+        let level = volume::next_volume(current, volume, force, &options, increment);
+        println!("Setting volume of {} to {:?}", control.name(), level);
+        control.apply(audio, level)?;
     }
 
     Ok(())
 }
 
-pub fn start_hid_thread(settings: Arc<RwLock<Settings>>, connected: Arc<RwLock<bool>>) -> Result<(), anyhow::Error> {
-    unsafe {
-        CoInitialize(None).unwrap();
+/// Serial number when the firmware reports one, VID:PID otherwise. The OS
+/// path would change between ports and reboots and lose the profile.
+pub fn device_id(device: &DeviceInfo) -> String {
+    match device.serial_number() {
+        Some(serial) if !serial.is_empty() => serial.to_string(),
+        _ => format!("{:04x}:{:04x}", device.vendor_id(), device.product_id()),
     }
-    let nvml = Nvml::init()?;
-    let gpu = nvml.device_by_index(0)?;
-    println!("Printing all available hid devices:");
+}
+
+fn open_failed(devices: &Devices, rule: &DeviceMatch, id: &str, path: &str, error: HidError) {
+    println!("Cannot open {}: {}", id, error.message);
+    if diagnostics::permission_denied(path) {
+        println!("{}\n{}", diagnostics::explain(path), diagnostics::udev_rule(rule));
+        connection::set_state(devices, id, None, ConnectionState::PermissionDenied { path: path.to_string() });
+    } else {
+        connection::set_errored(devices, id, error.message);
+    }
+}
 
+fn hid_paths(api: &HidApi) -> HashSet<String> {
+    api.device_list().map(|d| d.path().to_string_lossy().to_string()).collect()
+}
+
+/// Blocks until a hotplug event arrives. Without a hotplug monitor the
+/// device list is diffed against `known` every `POLL_INTERVAL` as well.
+/// False once nobody can send events any more.
+fn wait_for_change(api: &mut HidApi, events: &Receiver<HotplugEvent>, polling: bool, known: &HashSet<String>) -> bool {
     loop {
-        {
-            let mut conn = connected.write().unwrap();
-            *conn = false;
-        }
-        let api = HidApi::new().expect("Cannot create HidAPI");
-        let mut p_device: Option<&DeviceInfo> = None;
-        for device in api.device_list() {
-            //println!("vendor: {:#?}, product: {:#?}, interface: {}", device.vendor_id(), device.product_id(), device.interface_number());
-            if device.vendor_id() == 0xdeaf && device.product_id() == 0x0913 && device.interface_number() == 1 {
-                p_device = Some(device);
+        let event = if polling {
+            events.recv_timeout(hotplug::POLL_INTERVAL)
+        } else {
+            events.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        match event {
+            Ok(event) => {
+                println!("Hotplug: {:?}", event);
+                return true;
+            }
+            Err(RecvTimeoutError::Disconnected) => return false,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = api.refresh_devices() {
+                    println!("Cannot enumerate devices: {}", e);
+                }
+                if hid_paths(api) != *known {
+                    println!("Hotplug: device list changed");
+                    return true;
+                }
             }
         }
-        if p_device.is_none() {
-            println!("Cannot find device!");
-            sleep(Duration::from_secs(2));
-            continue;
-        }
+    }
+}
 
-        let device = p_device.unwrap().open_device(&api);
-        let device = match device {
-            Ok(m) => m,
-            Err(_e) => {
+/// Opens a worker for every matching device, then waits for hotplug events
+/// instead of re-enumerating on a timer.
+///
+/// Owns the process' only `HidApi`, hidapi refuses to create a second one.
+pub fn start_hid_thread(settings: Arc<RwLock<Settings>>, devices: Devices, listed: HidDevices, events: Receiver<HotplugEvent>, rescan: Sender<HotplugEvent>) -> Result<(), anyhow::Error> {
+    let mut api = HidApi::new()?;
+    let polling = !hotplug::spawn_watcher(rescan.clone());
+
+    loop {
+        if let Err(e) = api.refresh_devices() {
+            println!("Cannot enumerate devices: {}", e);
+        }
+        let known = hid_paths(&api);
+        let rule = settings.read().unwrap().device.clone();
+        *listed.write().unwrap() = mark_matched(api.device_list().map(HidDeviceEntry::from).collect(), &rule);
+        let mut present = Vec::new();
+        for info in api.device_list().filter(|d| rule.matches(&HidDeviceEntry::from(*d))) {
+            let id = device_id(info);
+            present.push(id.clone());
+            if connection::is_active(&devices, &id) {
                 continue;
             }
+
+            connection::set_state(&devices, &id, info.product_string().map(|s| s.to_string()), ConnectionState::Opening);
+            let device = match HidTransport::open(&api, info) {
+                Ok(m) => m,
+                Err(e) => {
+                    open_failed(&devices, &rule, &id, &info.path().to_string_lossy(), e);
+                    continue;
+                }
+            };
+
+            spawn_worker(Box::new(device), id, &settings, &devices, &rescan);
+        }
+
+        let (serial_settings, vendor_id, product_id) = {
+            let settings = settings.read().unwrap();
+            (settings.serial.clone(), settings.device.vendor_id, settings.device.product_id)
         };
-        {
-            let mut conn = connected.write().unwrap();
-            *conn = true;
+        if serial_settings.enabled {
+            let paths = match serial_settings.path {
+                Some(path) => vec![path],
+                None => serial::discover(vendor_id, product_id),
+            };
+            for path in paths {
+                present.push(path.clone());
+                if connection::is_active(&devices, &path) {
+                    continue;
+                }
+                connection::set_state(&devices, &path, Some("Serial".to_string()), ConnectionState::Opening);
+                let device = match SerialTransport::open(&path, serial_settings.baud_rate, vendor_id, product_id) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        open_failed(&devices, &rule, &path, &path, e);
+                        continue;
+                    }
+                };
+                spawn_worker(Box::new(device), path, &settings, &devices, &rescan);
+            }
+        }
+        connection::mark_missing(&devices, &present);
+
+        // block until something changes, then let any burst of events settle
+        if !wait_for_change(&mut api, &events, polling, &known) {
+            return Ok(());
         }
-        //device.set_blocking_mode(false)?;
-        match communicate_with_device(&device, &gpu, &settings) {
-            Err(_e) => continue,
-            _ => {}
+        sleep(SETTLE_DELAY);
+        while let Ok(event) = events.try_recv() {
+            println!("Hotplug: {:?}", event);
         }
     }
 }
 
-fn communicate_with_device(device: &HidDevice, gpu: &Device, settings: &Arc<RwLock<Settings>>) -> Result<(), HidError> {
-    let mut sys = System::new();
-    let mut now = Instant::now();
-    loop {
-        let packet = recv_packet(&device);
-        match packet {
-            Ok(p) => {
-                println!("Packet: {:?}", p);
-                match p.header {
-                    PacketHeader::GetVolume => {
-                        println!("Get volume!");
-                        let raw = p.raw();
-                        let application: u16 = u16::from(raw[1]) << 8 | u16::from(raw[0]);
-                        let apps = settings.read().unwrap();
-                        if apps.proc_list.contains_key(&application) {
-                            let application_title = apps.proc_list[&application].clone();
-                            let volume = get_volume(format!("{}.exe", application_title));
-                            let mut app = application_title.as_bytes().to_vec();
-                            if volume.is_ok() {
-                                let volume = volume.unwrap();
-                                // volume packet, append name of string
-                                let mut v = ((volume * 100.0).round() as u8).to_be_bytes().to_vec();
-                                v.append(&mut app);
-                                let volume_packet = Packet::new(PacketHeader::GetVolume, v);
-                                send_packet(&device, volume_packet)?;
-                            } else {
-                                println!("Cannot get volume!");
-                                let mut v = (255 as u8).to_be_bytes().to_vec();
-                                v.append(&mut app);
-                                let volume_packet = Packet::new(PacketHeader::GetVolume, v);
-                                send_packet(&device, volume_packet)?;
-                            }
-                        }
+fn spawn_worker(device: Box<dyn Transport>, id: String, settings: &Arc<RwLock<Settings>>, devices: &Devices, rescan: &Sender<HotplugEvent>) {
+    let settings = settings.clone();
+    let devices = devices.clone();
+    let rescan = rescan.clone();
+    std::thread::spawn(move || run_device(device, id, settings, devices, rescan));
+}
 
-                    }
-                    PacketHeader::ForceVolume | PacketHeader::ChangeVolume => {
-                        println!("Changing volume!");
-                        let raw = p.raw();
-                        let application: u16 = u16::from(raw[1]) << 8 | u16::from(raw[0]);
-                        let apps = settings.read().unwrap();
-                        let volume_up_or_down = if raw[2] == Volume::Up as u8 {
-                            Volume::Up
-                        } else {
-                            Volume::Down
-                        };
-                        if apps.proc_list.contains_key(&application) {
-                            let application_title = format!("{}.exe", apps.proc_list[&application]);
-                            println!("Changing volume {:?} on {}!", volume_up_or_down, application_title);
-                            let force = p.header == PacketHeader::ForceVolume;
-                            change_volume(settings.clone(), application_title, volume_up_or_down, force)?;
-                        }
-                    },
-                    _ => ()
-                }; 
-            }
+/// Worker for a single device, runs until it disconnects.
+fn run_device(device: Box<dyn Transport>, id: String, settings: Arc<RwLock<Settings>>, devices: Devices, rescan: Sender<HotplugEvent>) {
+    let audio = match audio::default_backend() {
+        Ok(audio) => Some(audio),
+        Err(e) => {
+            println!("No audio backend, volume keys won't work: {}", e);
+            None
+        }
+    };
+    let nvml = Nvml::init().ok();
+    let gpu = nvml.as_ref().and_then(|n| n.device_by_index(0).ok());
+
+    let capture_path = settings.read().unwrap().capture_path.clone();
+    let capture = capture_path.and_then(|path| match Capture::open(&path) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            println!("Cannot open capture file {}: {}", path, e.message);
+            None
+        }
+    });
+    let serial = device.info().serial_number;
+    let connected = |firmware: &FirmwareInfo| {
+        connection::set_state(&devices, &id, None, ConnectionState::Connected { firmware: firmware.clone(), serial: serial.clone() });
+    };
+    let result = match capture {
+        Some(capture) => communicate_with_device(&CapturingTransport::new(device.as_ref(), capture), &id, gpu.as_ref(), audio.as_deref(), &settings, connected),
+        None => communicate_with_device(device.as_ref(), &id, gpu.as_ref(), audio.as_deref(), &settings, connected),
+    };
+    device.close();
+    match result {
+        Ok(()) => connection::set_state(&devices, &id, None, ConnectionState::NoDevice),
+        Err(e) => {
+            println!("Device {} disconnected: {}", id, e.message);
+            connection::set_errored(&devices, &id, e.message);
+        }
+    }
+
+    // the device may still be plugged in (firmware reset, bad handshake), so look again
+    sleep(RETRY_DELAY);
+    let _ = rescan.send(HotplugEvent::Rescan);
+}
+
+/// Runs the device loop against a capture file instead of a real device.
+///
+/// With `mock_audio` every mapped application gets a fake session at half
+/// volume and the changes are printed instead of applied, so replays can run
+/// headless.
+pub fn replay(path: &str, settings: Arc<RwLock<Settings>>, mock_audio: bool) -> Result<(), HidError> {
+    let mock = mock_audio.then(|| {
+        let apps: Vec<String> = settings.read().unwrap().profile("replay").proc_list.values()
+            .filter_map(|target| match target {
+                Target::App(app) if !app.is_empty() => Some(app.clone()),
+                _ => None,
+            })
+            .collect();
+        MockBackend::with_apps(apps, 0.5)
+    });
+    let audio: Option<Box<dyn AudioBackend>> = match mock {
+        Some(_) => None,
+        None => audio::default_backend().ok(),
+    };
+    let backend = mock.as_ref().map(|m| m as &dyn AudioBackend).or(audio.as_deref());
+
+    let device = ReplayDevice::new(capture::load(path)?)?;
+    // the link has to seal exactly when the recorded one did
+    settings.write().unwrap().reliable_delivery = device.reliable();
+    if let Err(e) = communicate_with_device(&device, "replay", None, backend, &settings, |_| ()) {
+        println!("Device loop ended: {}", e.message);
+    }
+    println!("Replay finished with {} mismatched writes.", device.mismatches());
+    if let Some(mock) = mock {
+        for call in mock.calls() {
+            println!("Audio: {:?}", call);
+        }
+    }
+    Ok(())
+}
+
+fn handshake(device: &dyn Transport) -> Result<FirmwareInfo, HidError> {
+    // the hello exchange is always legacy framed so older firmware can still read it
+    let mut reassembler = Reassembler::new(Framing::Legacy);
+    let hello = HostMessage::Hello { protocol_version: PROTOCOL_VERSION };
+    send_packet(device, Framing::Legacy, &hello.encode())?;
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+        let packet = match recv_packet(device, &mut reassembler, HANDSHAKE_TIMEOUT)? {
+            Some(p) => p,
+            None => continue
+        };
+        match DeviceMessage::decode(&packet) {
+            Ok(DeviceMessage::Hello(firmware)) => return Ok(firmware),
+            Ok(message) => println!("Ignoring {:?} while waiting for hello!", message),
             Err(_) => ()
         }
-        if now.elapsed() >= std::time::Duration::from_secs(1) {
+    }
+    println!("Device did not answer hello, assuming legacy firmware.");
+    Ok(FirmwareInfo::legacy())
+}
+
+/// Runs the link until the device goes away.
+///
+/// The reader, writer and stats producer each get a thread; messages from the
+/// device are dispatched here so a slow audio call only delays the replies
+/// queued behind it, not stats or acks.
+fn communicate_with_device(device: &dyn Transport, id: &str, gpu: Option<&Device>, audio: Option<&dyn AudioBackend>, settings: &Arc<RwLock<Settings>>, connected: impl FnOnce(&FirmwareInfo)) -> Result<(), HidError> {
+    let firmware = handshake(device)?;
+    println!("Connected to {:?}, firmware: {:?}", device.info(), firmware);
+    connected(&firmware);
+    let reliable_delivery = settings.read().unwrap().reliable_delivery;
+    let config = LinkConfig::negotiate(&firmware, reliable_delivery);
+
+    let stop = AtomicBool::new(false);
+    let (inbound_tx, inbound_rx) = channel();
+    let (acks_tx, acks_rx) = channel();
+    let (replies_tx, replies_rx) = channel();
+    let (outbound_tx, outbound_rx) = sync_channel(OUTBOUND_QUEUE);
+
+    std::thread::scope(|scope| {
+        let reader = scope.spawn(|| link::run_reader(device, config, inbound_tx, acks_tx, replies_tx, &stop));
+        let writer = scope.spawn(|| link::run_writer(device, config, outbound_rx, acks_rx, replies_rx, &stop));
+        let stats_outbound = outbound_tx.clone();
+        let stats = scope.spawn(|| run_stats(id, &firmware, gpu, settings, stats_outbound, &stop));
+
+        dispatch(id, settings, audio, inbound_rx, &outbound_tx);
+        stop.store(true, Ordering::SeqCst);
+        drop(outbound_tx);
+
+        let mut result = Ok(());
+        for handle in [reader, writer] {
+            let joined = handle.join().unwrap_or_else(|_| Err(HidError::new("Link thread panicked!".to_string())));
+            if result.is_ok() {
+                result = joined;
+            }
+        }
+        let _ = stats.join();
+        result
+    })
+}
+
+/// Handles messages from the device until the reader hangs up.
+fn dispatch(id: &str, settings: &Arc<RwLock<Settings>>, audio: Option<&dyn AudioBackend>, inbound: Receiver<Packet>, outbound: &SyncSender<Packet>) {
+    for packet in inbound {
+        let result = match DeviceMessage::decode(&packet) {
+            Ok(DeviceMessage::GetVolume { key }) => handle_get_volume(settings, audio, id, key, outbound),
+            Ok(DeviceMessage::ChangeVolume { key, dir }) => handle_change_volume(settings, audio, id, key, dir, false, outbound),
+            Ok(DeviceMessage::ForceVolume { key, dir }) => handle_change_volume(settings, audio, id, key, dir, true, outbound),
+            Ok(message) => {
+                println!("Unhandled message: {:?}", message);
+                Ok(())
+            }
+            Err(_) => Ok(())
+        };
+        if let Err(e) = result {
+            println!("Cannot handle {:?}: {}", packet.header, e.message);
+        }
+    }
+}
+
+fn handle_get_volume(settings: &Arc<RwLock<Settings>>, audio: Option<&dyn AudioBackend>, id: &str, key: u16, outbound: &SyncSender<Packet>) -> Result<(), HidError> {
+    println!("Get volume!");
+    let target = match settings.read().unwrap().profile(id).proc_list.get(&key) {
+        Some(target) if !target.is_unset() => target.clone(),
+        _ => return Ok(())
+    };
+    if target.switches_output() {
+        let name = match audio.map(audio::default_output) {
+            Some(Ok(Some(device))) => device.name,
+            _ => String::default(),
+        };
+        return send(outbound, HostMessage::OutputDevice { name });
+    }
+    if target.is_scene() {
+        return send(outbound, HostMessage::Volume { volume: None, name: target.display_name() });
+    }
+    let volume = match audio.map(|audio| get_volume(audio, &target)) {
+        Some(Ok(volume)) => Some((volume * 100.0).round() as u8),
+        _ => {
+            println!("Cannot get volume!");
+            None
+        }
+    };
+    send(outbound, HostMessage::Volume { volume, name: target.display_name() })
+}
+
+fn send(outbound: &SyncSender<Packet>, message: HostMessage) -> Result<(), HidError> {
+    outbound.send(message.encode())
+        .map_err(|_| HidError::new("Link is closed!".to_string()))
+}
+
+fn handle_change_volume(settings: &Arc<RwLock<Settings>>, audio: Option<&dyn AudioBackend>, id: &str, key: u16, dir: Direction, force: bool, outbound: &SyncSender<Packet>) -> Result<(), HidError> {
+    println!("Changing volume!");
+    let audio = audio.ok_or_else(|| HidError::new("No audio backend!".to_string()))?;
+    let target = match settings.read().unwrap().profile(id).proc_list.get(&key) {
+        Some(target) if !target.is_unset() => target.clone(),
+        _ => return Ok(())
+    };
+    let output = match &target {
+        Target::Typed(TypedTarget::CycleOutput { devices }) => audio::cycle_output(audio, devices, dir)?,
+        Target::Typed(TypedTarget::SwitchOutput { device }) => audio::switch_output(audio, device)?,
+        Target::Typed(TypedTarget::Scene { scene }) => {
+            let scene = settings.read().unwrap().scenes.iter().find(|s| s.name == *scene).cloned()
+                .ok_or_else(|| HidError::new(format!("No scene named {}!", scene)))?;
+            // a fade would hold up every key pressed after this one
+            scenes::restore_in_background(scene);
+            return Ok(());
+        }
+        _ => {
+            println!("Changing volume {:?} on {:?}!", dir, target);
+            change_volume(audio, settings, id, key, &target, dir, force)?;
+            return Ok(());
+        }
+    };
+    println!("Default output is now {}", output.name);
+    send(outbound, HostMessage::OutputDevice { name: output.name })
+}
+
+/// Queues stats once a second. When the writer is backed up the sample is
+/// dropped rather than waited on, the next one will be fresher anyway.
+fn run_stats(id: &str, firmware: &FirmwareInfo, gpu: Option<&Device>, settings: &Arc<RwLock<Settings>>, outbound: SyncSender<Packet>, stop: &AtomicBool) {
+    if !firmware.capabilities.supports(Capabilities::STATS) {
+        return;
+    }
+    let mut sys = System::new();
+    while !stop.load(Ordering::SeqCst) {
+        sleep(STATS_INTERVAL);
+        let (show_stats, sensors) = {
             let set = settings.read().unwrap();
-            if set.show_stats {
-                send_stats(&mut sys, &device, &gpu)?;
-                println!("Send stats!");
-                //println!("Component size: {}", sys.components().len());
-                for component in sys.components() {
-                    println!("Component: {:?}", component);
-                }
-                now = Instant::now();
+            (set.profile(id).show_stats, set.temperature_sensors.clone())
+        };
+        if !show_stats {
+            continue;
+        }
+
+        let mut messages = vec![collect_stats(&mut sys, gpu)];
+        if firmware.capabilities.supports(Capabilities::TEMPERATURE) && !sensors.is_empty() {
+            let readings = temperature::read_temperatures(&sys, gpu, &sensors);
+            messages.push(HostMessage::Temperature { readings });
+        }
+        for message in messages {
+            match outbound.try_send(message.encode()) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => println!("Outbound queue full, dropping stats."),
+                Err(TrySendError::Disconnected(_)) => return,
             }
         }
     }
 }
 
-fn send_stats(sys: &mut System, device: &HidDevice, gpu: &Device) -> Result<(), HidError> {
+fn collect_stats(sys: &mut System, gpu: Option<&Device>) -> HostMessage {
     sys.refresh_cpu();
     sys.refresh_memory();
     sys.refresh_processes();
@@ -190,41 +543,269 @@ fn send_stats(sys: &mut System, device: &HidDevice, gpu: &Device) -> Result<(),
 
     let process_len = sys.processes().len();
 
-    let gpu_usage = gpu.utilization_rates()?;
+    let gpu_usage = match gpu.map(|gpu| gpu.utilization_rates()) {
+        Some(Ok(rates)) => rates.gpu,
+        _ => 0,
+    };
 
-    let mut buff: Vec<u8> = Vec::new();
-    buff.push(total_cpu_usage as u8);
-    buff.push(total_mem_percentage as u8);
-    buff.push((process_len >> 8) as u8);
-    buff.push((process_len >> 0) as u8);
-    buff.push(gpu_usage.gpu as u8);
+    HostMessage::Stats {
+        cpu: total_cpu_usage as u8,
+        mem: total_mem_percentage as u8,
+        procs: process_len as u16,
+        gpu: gpu_usage as u8,
+    }
+}
 
-    let stats = Packet::new(PacketHeader::Stats, buff);
-    send_packet(device, stats)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::audio::mock::MockCall;
+    use crate::packet::PacketHeader;
+    use crate::transport::LoopbackTransport;
 
-    Ok(())
-}
+    const SPOTIFY: u16 = 0x00C0;
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
 
-fn send_packet(device: &HidDevice, packet: Packet) -> Result<(), HidError> {
-    /*for item in &packet.raw()[..32] {
-        print!("{:#02x} ", item);
+    /// Firmware side of a loopback link.
+    struct Pad {
+        transport: LoopbackTransport,
+        reliable: bool,
+        sequence: u8,
+        reassembler: Reassembler,
     }
-    println!();*/
-    match device.write(&packet.to_packet_bytes()[..32]) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(HidError::new(e.to_string()))
+
+    impl Pad {
+        fn connect(transport: LoopbackTransport, capabilities: u8) -> Pad {
+            let mut buf = [0u8; 64];
+            let len = transport.read_timeout(&mut buf, REPLY_TIMEOUT.as_millis() as i32).unwrap();
+            assert_eq!(crate::packet::header_of(&buf[..len]), PacketHeader::Hello);
+            // legacy firmware reports start with the header byte, no magic
+            let hello = DeviceMessage::Hello(FirmwareInfo {
+                firmware_version: (1, 0, 0),
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: Capabilities::from_bits(capabilities),
+            });
+            transport.write(&hello.encode().to_reports(Framing::Legacy).unwrap()[0][2..]).unwrap();
+            Pad {
+                transport,
+                reliable: capabilities & Capabilities::RELIABLE != 0,
+                sequence: 0,
+                reassembler: Reassembler::new(Framing::Fragmented),
+            }
+        }
+
+        fn send(&mut self, message: DeviceMessage) {
+            let mut packet = message.encode();
+            if self.reliable {
+                packet = packet.seal(self.sequence);
+                self.sequence = self.sequence.wrapping_add(1);
+            }
+            send_packet(&self.transport, Framing::Fragmented, &packet).unwrap();
+        }
+
+        /// Next message from the host, acking it in reliable mode.
+        fn recv(&mut self) -> HostMessage {
+            let started = Instant::now();
+            while started.elapsed() < REPLY_TIMEOUT {
+                let packet = match recv_packet(&self.transport, &mut self.reassembler, Duration::from_millis(50)).unwrap() {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                if !self.reliable {
+                    return HostMessage::decode(&packet).unwrap();
+                }
+                if packet.header == PacketHeader::Ack {
+                    continue;
+                }
+                let (sequence, packet) = packet.unseal().unwrap();
+                send_packet(&self.transport, Framing::Fragmented, &Packet::ack(sequence)).unwrap();
+                return HostMessage::decode(&packet).unwrap();
+            }
+            panic!("Host sent nothing in time!");
+        }
+
+        /// Next message that isn't stats.
+        fn reply(&mut self) -> HostMessage {
+            loop {
+                match self.recv() {
+                    HostMessage::Stats { .. } | HostMessage::Temperature { .. } => continue,
+                    message => return message,
+                }
+            }
+        }
     }
-}
 
-fn recv_packet(device: &HidDevice) -> Result<Packet, HidError> {
-    let mut buf = [0u8; 32];
-    let res = device.read_timeout(&mut buf[..], 500)?;
-    if res > 0 {
-        for item in &buf {
-            print!("{:#02x} ", item);
+    impl Drop for Pad {
+        // unplugging ends the host side of the link, even when a test fails
+        fn drop(&mut self) {
+            self.transport.close();
         }
-        Packet::from(res, &mut buf[..])
-    } else {
-        Err(HidError::new("Packet not > 0 len!".to_string()))
     }
-}
\ No newline at end of file
+
+    fn pad_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.profile_mut(Some("pad")).proc_list.insert(SPOTIFY, Target::App("spotify".to_string()));
+        settings
+    }
+
+    fn run_link(capabilities: u8, audio: &MockBackend, test: impl FnOnce(&mut Pad)) {
+        let settings = Arc::new(RwLock::new(pad_settings()));
+        let (host, device) = LoopbackTransport::pair();
+        std::thread::scope(|scope| {
+            let link = scope.spawn(|| communicate_with_device(&host, "pad", None, Some(audio), &settings, |_| ()));
+            let mut pad = Pad::connect(device, capabilities);
+            test(&mut pad);
+            drop(pad);
+            assert!(link.join().unwrap().is_err(), "link outlived the device");
+        });
+    }
+
+    /// `run_link` with the host side recorded to `path`.
+    fn record_link(path: &str, capabilities: u8, audio: &MockBackend, test: impl FnOnce(&mut Pad)) {
+        let settings = Arc::new(RwLock::new(pad_settings()));
+        let (host, device) = LoopbackTransport::pair();
+        let capturing = CapturingTransport::new(&host, Capture::open(path).unwrap());
+        std::thread::scope(|scope| {
+            let link = scope.spawn(|| communicate_with_device(&capturing, "pad", None, Some(audio), &settings, |_| ()));
+            let mut pad = Pad::connect(device, capabilities);
+            test(&mut pad);
+            drop(pad);
+            assert!(link.join().unwrap().is_err(), "link outlived the device");
+        });
+    }
+
+    fn spotify() -> MockBackend {
+        MockBackend::with_apps(["spotify".to_string()], 0.5)
+    }
+
+    fn set_volumes(audio: &MockBackend) -> Vec<f32> {
+        audio.calls().into_iter().filter_map(|call| match call {
+            MockCall::SetVolume { volume, .. } => Some(volume),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn change_volume_applies_the_key_options() {
+        let mut settings = Settings::default();
+        settings.increment = 5;
+        settings.profile_mut(Some("pad")).volume.insert(SPOTIFY, crate::volume::VolumeOptions { step: Some(10), ..Default::default() });
+        let settings = Arc::new(RwLock::new(settings));
+        let audio = spotify();
+        let target = Target::App("spotify".to_string());
+
+        change_volume(&audio, &settings, "pad", SPOTIFY, &target, Direction::Up, false).unwrap();
+        change_volume(&audio, &settings, "pad", SPOTIFY, &target, Direction::Down, true).unwrap();
+        // muted controls count as silent, so the next press up unmutes at one step
+        change_volume(&audio, &settings, "pad", SPOTIFY, &target, Direction::Up, false).unwrap();
+        // other keys use the global increment
+        change_volume(&audio, &settings, "pad", 0x00C1, &target, Direction::Up, false).unwrap();
+
+        let session = || "spotify".to_string();
+        let calls = audio.calls();
+        assert!(matches!(&calls[0], MockCall::SetVolume { volume, .. } if (volume - 0.6).abs() < 0.001), "{:?}", calls);
+        assert_eq!(calls[1..3], [
+            MockCall::SetVolume { session: session(), volume: 0.0 },
+            MockCall::SetMute { session: session(), muted: true },
+        ]);
+        assert!(matches!(&calls[3], MockCall::SetVolume { volume, .. } if (volume - 0.1).abs() < 0.001), "{:?}", calls);
+        assert_eq!(calls[4], MockCall::SetMute { session: session(), muted: false });
+        assert!(matches!(&calls[5], MockCall::SetVolume { volume, .. } if (volume - 0.15).abs() < 0.001), "{:?}", calls);
+        assert_eq!(calls.len(), 6);
+    }
+
+    #[test]
+    fn get_volume_replies_with_the_level() {
+        for capabilities in [Capabilities::FRAGMENTS, Capabilities::FRAGMENTS | Capabilities::RELIABLE] {
+            let audio = spotify();
+            run_link(capabilities, &audio, |pad| {
+                pad.send(DeviceMessage::GetVolume { key: SPOTIFY });
+                assert_eq!(pad.reply(), HostMessage::Volume { volume: Some(50), name: "spotify".to_string() });
+            });
+            assert!(audio.calls().is_empty());
+        }
+    }
+
+    #[test]
+    fn change_volume_steps_by_the_increment() {
+        for capabilities in [Capabilities::FRAGMENTS, Capabilities::FRAGMENTS | Capabilities::RELIABLE] {
+            let audio = spotify();
+            run_link(capabilities, &audio, |pad| {
+                pad.send(DeviceMessage::ChangeVolume { key: SPOTIFY, dir: Direction::Up });
+                pad.send(DeviceMessage::ChangeVolume { key: SPOTIFY, dir: Direction::Up });
+                pad.send(DeviceMessage::ChangeVolume { key: SPOTIFY, dir: Direction::Down });
+                // messages are handled in order, so the reply comes after all three changes
+                pad.send(DeviceMessage::GetVolume { key: SPOTIFY });
+                assert_eq!(pad.reply(), HostMessage::Volume { volume: Some(55), name: "spotify".to_string() });
+            });
+            let volumes = set_volumes(&audio);
+            assert_eq!(volumes.len(), 3);
+            for (volume, expected) in volumes.iter().zip([0.55, 0.6, 0.55]) {
+                assert!((volume - expected).abs() < 0.001, "{} != {}", volume, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn force_volume_jumps_to_the_ends() {
+        let audio = spotify();
+        run_link(Capabilities::FRAGMENTS | Capabilities::RELIABLE, &audio, |pad| {
+            pad.send(DeviceMessage::ForceVolume { key: SPOTIFY, dir: Direction::Up });
+            pad.send(DeviceMessage::GetVolume { key: SPOTIFY });
+            assert_eq!(pad.reply(), HostMessage::Volume { volume: Some(100), name: "spotify".to_string() });
+            pad.send(DeviceMessage::ForceVolume { key: SPOTIFY, dir: Direction::Down });
+            pad.send(DeviceMessage::GetVolume { key: SPOTIFY });
+            assert_eq!(pad.reply(), HostMessage::Volume { volume: Some(0), name: "spotify".to_string() });
+        });
+        assert_eq!(audio.calls(), vec![
+            MockCall::SetVolume { session: "spotify".to_string(), volume: 1.0 },
+            MockCall::SetVolume { session: "spotify".to_string(), volume: 0.0 },
+            MockCall::SetMute { session: "spotify".to_string(), muted: true },
+        ]);
+    }
+
+    #[test]
+    fn unmapped_keys_are_ignored() {
+        let audio = spotify();
+        run_link(Capabilities::FRAGMENTS, &audio, |pad| {
+            pad.send(DeviceMessage::ChangeVolume { key: 0x00C1, dir: Direction::Up });
+            pad.send(DeviceMessage::GetVolume { key: SPOTIFY });
+            pad.reply();
+        });
+        assert!(audio.calls().is_empty());
+    }
+
+    #[test]
+    fn stats_are_sent_while_enabled() {
+        let audio = spotify();
+        run_link(Capabilities::FRAGMENTS | Capabilities::STATS, &audio, |pad| {
+            assert!(matches!(pad.recv(), HostMessage::Stats { .. }));
+        });
+    }
+
+    #[test]
+    fn replays_a_reliable_capture() {
+        let path = std::env::temp_dir().join(format!("macropad-replay-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let capabilities = Capabilities::FRAGMENTS | Capabilities::RELIABLE | Capabilities::STATS;
+        let recorded = spotify();
+        record_link(path, capabilities, &recorded, |pad| {
+            // stats take sequence numbers the replay won't hand out the same way
+            assert!(matches!(pad.recv(), HostMessage::Stats { .. }));
+            pad.send(DeviceMessage::ChangeVolume { key: SPOTIFY, dir: Direction::Up });
+            pad.send(DeviceMessage::GetVolume { key: SPOTIFY });
+            assert_eq!(pad.reply(), HostMessage::Volume { volume: Some(55), name: "spotify".to_string() });
+        });
+
+        let device = ReplayDevice::new(capture::load(path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(path);
+        assert!(device.reliable());
+        let replayed = spotify();
+        let settings = Arc::new(RwLock::new(pad_settings()));
+        assert!(communicate_with_device(&device, "pad", None, Some(&replayed), &settings, |_| ()).is_err());
+        assert_eq!(device.mismatches(), 0);
+        assert_eq!(replayed.calls(), recorded.calls());
+    }
+}
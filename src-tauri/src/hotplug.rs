@@ -0,0 +1,80 @@
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use crate::error::HidError;
+
+/// How often the device list is diffed when there is no hotplug monitor.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HotplugEvent {
+    /// A HID device or serial port appeared, carries its device node or hidapi path.
+    Attached(String),
+    Detached(String),
+    /// Something other than the device list changed, like the matching rule.
+    Rescan,
+}
+
+/// Starts a udev monitor thread that reports HID devices and serial ports
+/// coming and going.
+///
+/// Returns false when there is no monitor, on other platforms or when udev
+/// isn't available. The caller then has to poll its own device list, hidapi
+/// allows only one context per process so this can't do it.
+#[cfg(target_os = "linux")]
+pub fn spawn_watcher(events: Sender<HotplugEvent>) -> bool {
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        if let Err(e) = watch_udev(&events, &started_tx) {
+            println!("udev monitor unavailable, polling instead: {}", e.message);
+            let _ = started_tx.send(false);
+        }
+    });
+    started_rx.recv().unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn spawn_watcher(_events: Sender<HotplugEvent>) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn watch_udev(events: &Sender<HotplugEvent>, started: &Sender<bool>) -> Result<(), HidError> {
+    use std::os::unix::io::AsRawFd;
+
+    let socket = udev::MonitorBuilder::new()?
+        .match_subsystem("hidraw")?
+        .match_subsystem("tty")?
+        .listen()?;
+    let _ = started.send(true);
+    let mut fds = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        // the monitor socket is non-blocking, so wait for it to become readable
+        let result = unsafe { libc::poll(&mut fds, 1, -1) };
+        if result < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error.into());
+        }
+
+        for event in socket.iter() {
+            let node = event.devnode()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|| event.syspath().to_string_lossy().to_string());
+            let hotplug = match event.event_type() {
+                udev::EventType::Add => HotplugEvent::Attached(node),
+                udev::EventType::Remove => HotplugEvent::Detached(node),
+                _ => continue,
+            };
+            if events.send(hotplug).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
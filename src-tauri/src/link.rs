@@ -0,0 +1,197 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use crate::error::HidError;
+use crate::packet::{Capabilities, FirmwareInfo, Framing, Packet, PacketHeader, Reassembler, REPORT_SIZE};
+use crate::transport::Transport;
+
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_RETRIES: usize = 3;
+// how long the reader and writer block before checking whether to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// how long an ack for the device may wait while the writer is idle
+const REPLY_INTERVAL: Duration = Duration::from_millis(5);
+pub const OUTBOUND_QUEUE: usize = 16;
+
+/// How the link behaves once the handshake is done.
+///
+/// In reliable mode every outbound packet is sealed with a sequence number and
+/// checksum and retransmitted until the device acks it. Inbound packets are
+/// acked, or nacked when the checksum fails, before they are dispatched.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConfig {
+    pub framing: Framing,
+    pub reliable: bool,
+}
+
+impl LinkConfig {
+    pub fn negotiate(firmware: &FirmwareInfo, reliable_delivery: bool) -> Self {
+        let framing = Framing::for_capabilities(&firmware.capabilities);
+        // sealed packets need the length prefix to find the checksum
+        let reliable = reliable_delivery
+            && framing == Framing::Fragmented
+            && firmware.capabilities.supports(Capabilities::RELIABLE);
+        Self {
+            framing,
+            reliable,
+        }
+    }
+}
+
+fn stop_on_error(stop: &AtomicBool, result: Result<(), HidError>) -> Result<(), HidError> {
+    if result.is_err() {
+        stop.store(true, Ordering::SeqCst);
+    }
+    result
+}
+
+/// Reads packets and hands them to the dispatcher through `inbound`. Acks and
+/// nacks for our own packets go to the writer through `acks`, the ones we owe
+/// the device through `replies`, so only the writer ever writes.
+pub fn run_reader(device: &dyn Transport, config: LinkConfig, inbound: Sender<Packet>, acks: Sender<(PacketHeader, u8)>, replies: Sender<Packet>, stop: &AtomicBool) -> Result<(), HidError> {
+    stop_on_error(stop, read_loop(device, config, inbound, acks, replies, stop))
+}
+
+fn read_loop(device: &dyn Transport, config: LinkConfig, inbound: Sender<Packet>, acks: Sender<(PacketHeader, u8)>, replies: Sender<Packet>, stop: &AtomicBool) -> Result<(), HidError> {
+    let mut reassembler = Reassembler::new(config.framing);
+    let mut last_received: Option<u8> = None;
+    while !stop.load(Ordering::SeqCst) {
+        let packet = match recv_packet(device, &mut reassembler, POLL_INTERVAL)? {
+            Some(p) => p,
+            None => continue
+        };
+
+        if !config.reliable {
+            if inbound.send(packet).is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+
+        if packet.header == PacketHeader::Ack || packet.header == PacketHeader::Nack {
+            if let Some(sequence) = packet.sequence() {
+                let _ = acks.send((packet.header, sequence));
+            }
+            continue;
+        }
+
+        let sequence = packet.sequence().unwrap_or_default();
+        match packet.unseal() {
+            Ok((sequence, packet)) => {
+                if replies.send(Packet::ack(sequence)).is_err() {
+                    return Ok(());
+                }
+                // our ack got lost and the device sent it again
+                if last_received == Some(sequence) {
+                    continue;
+                }
+                last_received = Some(sequence);
+                if inbound.send(packet).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                println!("Rejecting packet {}: {}", sequence, e.message);
+                if replies.send(Packet::nack(sequence)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drains the outbound queue onto the device, retransmitting in reliable mode
+/// until each packet is acked.
+///
+/// `replies` skip the queue: they go out between packets, never in the middle
+/// of a fragmented one, so they don't wait behind stats.
+pub fn run_writer(device: &dyn Transport, config: LinkConfig, outbound: Receiver<Packet>, acks: Receiver<(PacketHeader, u8)>, replies: Receiver<Packet>, stop: &AtomicBool) -> Result<(), HidError> {
+    stop_on_error(stop, write_loop(device, config, outbound, acks, replies, stop))
+}
+
+fn write_loop(device: &dyn Transport, config: LinkConfig, outbound: Receiver<Packet>, acks: Receiver<(PacketHeader, u8)>, replies: Receiver<Packet>, stop: &AtomicBool) -> Result<(), HidError> {
+    let mut sequence: u8 = 0;
+    loop {
+        send_replies(device, config.framing, &replies)?;
+        let packet = match outbound.recv_timeout(REPLY_INTERVAL) {
+            Ok(p) => p,
+            Err(RecvTimeoutError::Timeout) if !stop.load(Ordering::SeqCst) => continue,
+            Err(_) => return Ok(())
+        };
+
+        if !config.reliable {
+            send_packet(device, config.framing, &packet)?;
+            continue;
+        }
+
+        let sealed = packet.seal(sequence);
+        let mut acked = false;
+        for attempt in 0..=MAX_RETRIES {
+            if attempt > 0 {
+                println!("Retransmitting packet {} ({}/{})", sequence, attempt, MAX_RETRIES);
+            }
+            send_packet(device, config.framing, &sealed)?;
+            if wait_for_ack(device, config.framing, &acks, &replies, sequence)? {
+                acked = true;
+                break;
+            }
+        }
+        if !acked {
+            return Err(HidError::new(format!("Packet {} was never acknowledged!", sequence)));
+        }
+        sequence = sequence.wrapping_add(1);
+    }
+}
+
+/// False when the device nacked the packet or nothing came back in time.
+/// Keeps sending `replies` meanwhile, the device may be waiting on one of
+/// them before it acks.
+fn wait_for_ack(device: &dyn Transport, framing: Framing, acks: &Receiver<(PacketHeader, u8)>, replies: &Receiver<Packet>, sequence: u8) -> Result<bool, HidError> {
+    let started = Instant::now();
+    while let Some(remaining) = ACK_TIMEOUT.checked_sub(started.elapsed()) {
+        send_replies(device, framing, replies)?;
+        match acks.recv_timeout(remaining.min(REPLY_INTERVAL)) {
+            Ok((PacketHeader::Ack, s)) if s == sequence => return Ok(true),
+            Ok((PacketHeader::Nack, s)) if s == sequence => return Ok(false),
+            Ok((header, s)) => println!("Ignoring stale {:?} for {}", header, s),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return Ok(false)
+        }
+    }
+    Ok(false)
+}
+
+fn send_replies(device: &dyn Transport, framing: Framing, replies: &Receiver<Packet>) -> Result<(), HidError> {
+    for reply in replies.try_iter() {
+        send_packet(device, framing, &reply)?;
+    }
+    Ok(())
+}
+
+pub fn send_packet(device: &dyn Transport, framing: Framing, packet: &Packet) -> Result<(), HidError> {
+    for report in packet.to_reports(framing)? {
+        device.write(&report)?;
+    }
+    Ok(())
+}
+
+/// `None` on timeout or when a report couldn't be parsed, an error only when
+/// the device itself failed.
+pub fn recv_packet(device: &dyn Transport, reassembler: &mut Reassembler, timeout: Duration) -> Result<Option<Packet>, HidError> {
+    let mut buf = [0u8; REPORT_SIZE];
+    loop {
+        let res = device.read_timeout(&mut buf[..], timeout.as_millis() as i32)?;
+        if res == 0 {
+            return Ok(None);
+        }
+        match reassembler.push(&buf[..res]) {
+            Ok(Some(packet)) => return Ok(Some(packet)),
+            Ok(None) => (),
+            Err(e) => {
+                println!("Bad report: {}", e.message);
+                return Ok(None);
+            }
+        }
+    }
+}
@@ -4,20 +4,45 @@
 )]
 
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{channel, Sender};
 use auto_launch::AutoLaunchBuilder;
 use directories::ProjectDirs;
+use nvml_wrapper::Nvml;
 use serde::{Serialize, Deserialize};
 use sysinfo::Signal::Sys;
 use sysinfo::{ProcessExt, System, SystemExt};
-use tauri::{CustomMenuItem, GlobalWindowEvent, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem, Window, WindowEvent, WindowUrl, Wry};
-use crate::audio::AudioEndpoint;
+use tauri::{CustomMenuItem, GlobalWindowEvent, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem, SystemTraySubmenu, Window, WindowEvent, WindowUrl, Wry};
 use crate::error::HidError;
+use crate::audio::AudioDevice;
+use crate::connection::{DeviceStatus, Devices};
+use crate::diagnostics::Diagnosis;
+use crate::ducking::DuckingRule;
+use crate::hid::{DeviceMatch, HidDeviceEntry, HidDevices};
+use crate::hotplug::HotplugEvent;
+use crate::mapping::Target;
+use crate::scenes::{Scene, SnapshotScope};
+use crate::serial::SerialSettings;
+use crate::volume::VolumeOptions;
 
 mod audio;
+mod capture;
+mod connection;
+mod diagnostics;
+mod ducking;
 mod error;
+mod foreground;
 mod packet;
+mod serial;
 mod hid;
+mod hotplug;
+mod link;
+mod mapping;
+mod scenes;
+mod temperature;
+mod transport;
+mod volume;
 
 macro_rules! collection {
     // map-like
@@ -28,80 +53,167 @@ macro_rules! collection {
 
 pub struct State {
     pub settings: Arc<RwLock<Settings>>,
-    pub connected: Arc<RwLock<bool>>,
+    pub devices: Devices,
+    pub hid_devices: HidDevices,
+    pub hotplug: Mutex<Sender<HotplugEvent>>,
 }
 
+/// Per device key mappings and stats toggle.
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
-pub struct Settings {
-    pub proc_list: HashMap<u16, String>,
+pub struct Profile {
+    pub proc_list: HashMap<u16, Target>,
     pub show_stats: bool,
+    /// Step, curve and limits of keys that don't use the defaults.
+    #[serde(default)]
+    pub volume: HashMap<u16, VolumeOptions>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        let items: HashMap<u16, Target> = collection! {
+            0x00C0 => Target::App("spotify".to_string()),
+            0x00C1 => Target::default(),
+            0x00C2 => Target::default(),
+            0x00C3 => Target::default(),
+            0x00C4 => Target::default(),
+            0x00C5 => Target::default(),
+            0x00C6 => Target::default(),
+            0x00C7 => Target::default(),
+            0x00C8 => Target::default(),
+            0x00C9 => Target::default(),
+            0x00CA => Target::default(),
+            0x00CB => Target::default(),
+            0x00CC => Target::default(),
+            0x00CD => Target::default(),
+            0x00CE => Target::default(),
+            0x00CF => Target::default(),
+            0x00D0 => Target::default(),
+            0x00D1 => Target::default(),
+            0x00D2 => Target::default(),
+            0x00D3 => Target::default(),
+            0x00D4 => Target::default(),
+            0x00D5 => Target::default(),
+            0x00D6 => Target::default(),
+            0x00D7 => Target::default(),
+            0x00D8 => Target::default(),
+            0x00D9 => Target::default(),
+            0x00DA => Target::default(),
+            0x00DB => Target::default(),
+            0x00DC => Target::default(),
+            0x00DD => Target::default(),
+            0x00DE => Target::default(),
+            0x00DF => Target::default(),
+        };
+
+        Profile {
+            proc_list: items,
+            show_stats: true,
+            volume: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Settings {
+    /// Used by every device without a profile of its own, copied into
+    /// `profiles` the first time one is customized.
+    #[serde(default)]
+    pub defaults: Profile,
+    /// Keyed by device id, see `hid::device_id`.
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
     pub increment: i32,
+    #[serde(default = "default_true")]
+    pub reliable_delivery: bool,
+    #[serde(default = "temperature::default_sensors")]
+    pub temperature_sensors: Vec<String>,
+    /// When set, every HID report is appended to this file.
+    #[serde(default)]
+    pub capture_path: Option<String>,
+    #[serde(default)]
+    pub device: DeviceMatch,
+    #[serde(default)]
+    pub serial: SerialSettings,
+    #[serde(default)]
+    pub scenes: Vec<Scene>,
+    #[serde(default)]
+    pub ducking: Vec<DuckingRule>,
+    // configs from before profiles kept the mappings at the top level,
+    // `migrate` moves them into `defaults`
+    #[serde(default, skip_serializing)]
+    proc_list: Option<HashMap<u16, Target>>,
+    #[serde(default, skip_serializing)]
+    show_stats: Option<bool>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn config_path() -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("com", "zacbre", "macropad-ui")?;
+    Some(proj_dirs.config_dir().with_file_name("config.json"))
 }
 
 impl Settings {
-    pub fn import_json() -> Result<Settings, HidError> {
-        if let Some(proj_dirs) = ProjectDirs::from("com", "zacbre", "macropad-ui") {
-            let config_dir = proj_dirs.config_dir();
-            std::fs::create_dir_all(config_dir)?;
-            let contents = std::fs::read_to_string(config_dir.with_file_name("config.json"))?;
-            let settings = serde_json::from_str::<Settings>(contents.as_str())?;
-            return Ok(settings);
+    pub fn profile(&self, device: &str) -> &Profile {
+        self.profiles.get(device).unwrap_or(&self.defaults)
+    }
+
+    /// `None` edits the defaults, a device id edits (and creates) that device's profile.
+    pub fn profile_mut(&mut self, device: Option<&str>) -> &mut Profile {
+        match device {
+            Some(id) => self.profiles.entry(id.to_string()).or_insert_with(|| self.defaults.clone()),
+            None => &mut self.defaults,
         }
-        Err(HidError::new("Cannot import json.".to_string()))
     }
 
-    fn default() -> Self {
-        let items: HashMap<u16, String> = collection! {
-            0x00C0 => "spotify".to_string(),
-            0x00C1 => String::default(),
-            0x00C2 => String::default(),
-            0x00C3 => String::default(),
-            0x00C4 => String::default(),
-            0x00C5 => String::default(),
-            0x00C6 => String::default(),
-            0x00C7 => String::default(),
-            0x00C8 => String::default(),
-            0x00C9 => String::default(),
-            0x00CA => String::default(),
-            0x00CB => String::default(),
-            0x00CC => String::default(),
-            0x00CD => String::default(),
-            0x00CE => String::default(),
-            0x00CF => String::default(),
-            0x00D0 => String::default(),
-            0x00D1 => String::default(),
-            0x00D2 => String::default(),
-            0x00D3 => String::default(),
-            0x00D4 => String::default(),
-            0x00D5 => String::default(),
-            0x00D6 => String::default(),
-            0x00D7 => String::default(),
-            0x00D8 => String::default(),
-            0x00D9 => String::default(),
-            0x00DA => String::default(),
-            0x00DB => String::default(),
-            0x00DC => String::default(),
-            0x00DD => String::default(),
-            0x00DE => String::default(),
-            0x00DF => String::default(),
-        };
+    pub fn from_json(contents: &str) -> Result<Settings, HidError> {
+        let mut settings = serde_json::from_str::<Settings>(contents)?;
+        settings.migrate();
+        Ok(settings)
+    }
+
+    fn migrate(&mut self) {
+        if let Some(proc_list) = self.proc_list.take() {
+            self.defaults.proc_list = proc_list;
+        }
+        if let Some(show_stats) = self.show_stats.take() {
+            self.defaults.show_stats = show_stats;
+        }
+    }
+
+    pub fn import_json() -> Result<Settings, HidError> {
+        let path = config_path().ok_or_else(|| HidError::new("Cannot import json.".to_string()))?;
+        let contents = std::fs::read_to_string(path)?;
+        Settings::from_json(&contents)
+    }
 
+    fn default() -> Self {
         Settings {
-            proc_list: items,
-            show_stats: true,
+            defaults: Profile::default(),
+            profiles: HashMap::new(),
             increment: 5,
+            reliable_delivery: true,
+            temperature_sensors: temperature::default_sensors(),
+            capture_path: None,
+            device: DeviceMatch::default(),
+            serial: SerialSettings::default(),
+            scenes: Vec::new(),
+            ducking: Vec::new(),
+            proc_list: None,
+            show_stats: None,
         }
     }
 
     fn save_json(&self) -> Result<(), HidError> {
-        if let Some(proj_dirs) = ProjectDirs::from("com", "zacbre", "macropad-ui") {
-            let config_dir = proj_dirs.config_dir();
-            std::fs::create_dir_all(config_dir)?;
-            let serialized = serde_json::to_string(&self)?;
-            std::fs::write(config_dir.with_file_name("config.json"), serialized.as_bytes())?;
-            return Ok(());
+        let path = config_path().ok_or_else(|| HidError::new("Cannot save json!".to_string()))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
         }
-        Err(HidError::new("Cannot save json!".to_string()))
+        let serialized = serde_json::to_string(&self)?;
+        std::fs::write(path, serialized.as_bytes())?;
+        Ok(())
     }
 }
 
@@ -109,7 +221,7 @@ impl Settings {
 #[derive(Debug, Serialize, Deserialize)]
 struct Mapping {
     pub key: u16,
-    pub value: String
+    pub value: Target
 }
 
 #[tauri::command]
@@ -119,29 +231,84 @@ fn get_volume_inc(state: tauri::State<State>) -> i32 {
 }
 
 #[tauri::command]
-fn set_increment(state: tauri::State<State>, vol: i32) {
+fn set_increment(state: tauri::State<State>, vol: i32) -> Result<(), String> {
     {
         let mut sett = state.settings.write().unwrap();
         sett.increment = vol;
     }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)
+}
+
+#[tauri::command]
+fn get_process_list() -> Vec<String> {
+    match audio::platform_backend().and_then(|audio| audio.sessions()) {
+        Ok(sessions) => sessions.into_iter().map(|s| audio::executable_stem(&s.name)).collect::<Vec<String>>(),
+        Err(_) => vec![]
+    }
+}
+
+#[tauri::command]
+fn get_audio_devices() -> Vec<AudioDevice> {
+    audio::platform_backend().and_then(|audio| audio.devices()).unwrap_or_default()
+}
+
+#[tauri::command]
+fn get_temperature_sensors() -> Vec<String> {
+    let mut sys = System::new();
+    let nvml = Nvml::init().ok();
+    let gpu = nvml.as_ref().and_then(|n| n.device_by_index(0).ok());
+    temperature::available_sensors(&mut sys, gpu.as_ref())
+}
+
+#[tauri::command]
+fn set_temperature_sensors(state: tauri::State<State>, sensors: Vec<String>) -> Result<(), String> {
     {
-        let settings = state.settings.read().unwrap();
-        settings.save_json();
+        let mut settings = state.settings.write().unwrap();
+        settings.temperature_sensors = sensors;
     }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)
 }
 
 #[tauri::command]
-fn get_process_list() -> Vec<String> {
-    if let Ok(audio_endpoints) = audio::enumerate_applications() {
-        audio_endpoints.into_iter().map(|p| p.name).collect::<Vec<String>>()
-    } else {
-        vec![]
+fn list_hid_devices(state: tauri::State<State>) -> Vec<HidDeviceEntry> {
+    // matched against the current rule, the list may predate a rule change
+    let rule = state.settings.read().unwrap().device.clone();
+    hid::mark_matched(state.hid_devices.read().unwrap().clone(), &rule)
+}
+
+#[tauri::command]
+fn set_device_match(state: tauri::State<State>, rule: Option<DeviceMatch>) -> Result<(), String> {
+    {
+        let mut settings = state.settings.write().unwrap();
+        settings.device = rule.unwrap_or_default();
     }
+    state.settings.read().unwrap().save_json().map_err(|e| e.message)?;
+    let _ = state.hotplug.lock().unwrap().send(HotplugEvent::Rescan);
+    Ok(())
 }
 
 #[tauri::command]
-fn get_connected_state(state: tauri::State<State>) -> bool {
-    state.connected.read().unwrap().clone()
+fn get_diagnostics(state: tauri::State<State>) -> Diagnosis {
+    let rule = state.settings.read().unwrap().device.clone();
+    diagnostics::diagnose(&rule, &state.hid_devices.read().unwrap())
+}
+
+#[tauri::command]
+fn install_udev_rule(state: tauri::State<State>) -> Result<(), String> {
+    let rule = state.settings.read().unwrap().device.clone();
+    diagnostics::install_rule(&rule).map_err(|e| e.message)?;
+    let _ = state.hotplug.lock().unwrap().send(HotplugEvent::Rescan);
+    Ok(())
+}
+
+/// Every device seen since startup with its state and recent errors.
+#[tauri::command]
+fn get_connection_state(state: tauri::State<State>) -> Vec<DeviceStatus> {
+    let mut devices: Vec<DeviceStatus> = state.devices.read().unwrap().values().cloned().collect();
+    devices.sort_by(|a, b| a.id.cmp(&b.id));
+    devices
 }
 
 #[tauri::command]
@@ -150,21 +317,115 @@ fn get_apps(state: tauri::State<State>) -> Settings {
 }
 
 #[tauri::command]
-fn set_mapping(state: tauri::State<State>, mapping: Mapping) {
+fn set_mapping(state: tauri::State<State>, mapping: Mapping, device: Option<String>) -> Result<(), String> {
     {
         let mut settings = state.settings.write().unwrap();
-        if settings.proc_list.contains_key(&mapping.key) {
-            if let Some(x) = settings.proc_list.get_mut(&mapping.key) {
-                *x = mapping.value;
-            }
+        let profile = settings.profile_mut(device.as_deref());
+        if let Some(x) = profile.proc_list.get_mut(&mapping.key) {
+            *x = mapping.value;
         }
     }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)
+}
+
+#[tauri::command]
+fn set_show_stats(state: tauri::State<State>, show: bool, device: Option<String>) -> Result<(), String> {
+    {
+        let mut settings = state.settings.write().unwrap();
+        settings.profile_mut(device.as_deref()).show_stats = show;
+    }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)
+}
+
+/// `None` puts the key back on the default step and curve.
+#[tauri::command]
+fn set_volume_options(state: tauri::State<State>, key: u16, options: Option<VolumeOptions>, device: Option<String>) -> Result<(), String> {
     {
+        let mut settings = state.settings.write().unwrap();
+        let profile = settings.profile_mut(device.as_deref());
+        match options {
+            Some(options) => profile.volume.insert(key, options),
+            None => profile.volume.remove(&key),
+        };
+    }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)
+}
+
+/// Saves the current levels as `name`, replacing a scene of the same name.
+#[tauri::command]
+fn save_scene(app: tauri::AppHandle, state: tauri::State<State>, name: String, scope: SnapshotScope, fade_ms: u64, device: Option<String>) -> Result<(), String> {
+    let mapped: Vec<Target> = {
         let settings = state.settings.read().unwrap();
-        settings.save_json();
+        let profile = match device.as_deref() {
+            Some(id) => settings.profile(id),
+            None => &settings.defaults,
+        };
+        profile.proc_list.values().cloned().collect()
+    };
+    let entries = audio::platform_backend()
+        .and_then(|audio| scenes::snapshot(&*audio, scope, &mapped))
+        .map_err(|e| e.to_string())?;
+    {
+        let mut settings = state.settings.write().unwrap();
+        let scene = Scene { name, entries, fade_ms };
+        match settings.scenes.iter_mut().find(|s| s.name == scene.name) {
+            Some(existing) => *existing = scene,
+            None => settings.scenes.push(scene),
+        }
+    }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)?;
+    app.tray_handle().set_menu(tray_menu(&settings)).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn delete_scene(app: tauri::AppHandle, state: tauri::State<State>, name: String) -> Result<(), String> {
+    {
+        let mut settings = state.settings.write().unwrap();
+        settings.scenes.retain(|s| s.name != name);
+    }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)?;
+    app.tray_handle().set_menu(tray_menu(&settings)).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn restore_scene(state: tauri::State<State>, name: String) {
+    let scene = state.settings.read().unwrap().scenes.iter().find(|s| s.name == name).cloned();
+    if let Some(scene) = scene {
+        scenes::restore_in_background(scene);
     }
 }
 
+#[tauri::command]
+fn set_ducking_rules(state: tauri::State<State>, rules: Vec<DuckingRule>) -> Result<(), String> {
+    {
+        let mut settings = state.settings.write().unwrap();
+        settings.ducking = rules;
+    }
+    let settings = state.settings.read().unwrap();
+    settings.save_json().map_err(|e| e.message)
+}
+
+/// Tray items for scenes are `scene:<name>`.
+fn tray_menu(settings: &Settings) -> SystemTrayMenu {
+    let mut show_stats = CustomMenuItem::new("show_stats".to_string(), "Send Stats to Macropad");
+    show_stats.selected = settings.defaults.show_stats;
+    let mut menu = SystemTrayMenu::new().add_item(show_stats);
+    if !settings.scenes.is_empty() {
+        let scenes = settings.scenes.iter().fold(SystemTrayMenu::new(), |menu, scene| {
+            menu.add_item(CustomMenuItem::new(format!("scene:{}", scene.name), scene.name.clone()))
+        });
+        menu = menu.add_submenu(SystemTraySubmenu::new("Restore Scene", scenes));
+    }
+    menu.add_item(CustomMenuItem::new("quit".to_string(), "Quit"))
+}
+
 #[tauri::command]
 async fn open_window(handle: tauri::AppHandle, url: String) {
     tauri::WindowBuilder::new(
@@ -180,6 +441,28 @@ async fn open_window(handle: tauri::AppHandle, url: String) {
 }
 
 fn main() {
+    // `macropad-ui --replay <capture> [--mock-audio]` runs the device loop against a capture file and exits.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|a| a == "--replay") {
+        let settings = Settings::import_json().unwrap_or_else(|_| Settings::default());
+        match args.get(i + 1) {
+            Some(path) => {
+                if let Err(e) = hid::replay(path, Arc::new(RwLock::new(settings)), args.iter().any(|a| a == "--mock-audio")) {
+                    println!("Cannot replay {}: {}", path, e.message);
+                }
+            }
+            None => println!("Usage: macropad-ui --replay <capture file> [--mock-audio]"),
+        }
+        return;
+    }
+    // `macropad-ui --udev-rule` prints the rule for the configured device for manual install.
+    if args.iter().any(|a| a == "--udev-rule") {
+        let settings = Settings::import_json().unwrap_or_else(|_| Settings::default());
+        print!("{}", diagnostics::udev_rule(&settings.device));
+        println!("# save as {}, then run: udevadm control --reload-rules && udevadm trigger", diagnostics::RULE_PATH);
+        return;
+    }
+
     let auto = AutoLaunchBuilder::new()
         .set_app_name("Macropad-UI")
         .set_app_path(std::env::current_exe().unwrap().to_str().unwrap())
@@ -195,33 +478,45 @@ fn main() {
     // import the hashmap via json file.
     let settings = match Settings::import_json() {
         Ok(r) => r,
-        Err(_) => {
-            println!("Could not read json...creating new default file.");
-            let default = Settings::default();
-            default.save_json();
-            default
-        }
+        Err(e) => match config_path() {
+            Some(path) if path.exists() => {
+                // keep the unreadable file around, the next save replaces config.json
+                let backup = path.with_extension("json.bak");
+                println!("Could not read {}: {}, starting from defaults, the old file is kept as {}.", path.display(), e.message, backup.display());
+                if let Err(e) = std::fs::copy(&path, &backup) {
+                    println!("Cannot back up {}: {}", path.display(), e);
+                }
+                Settings::default()
+            }
+            _ => {
+                println!("Could not read json...creating new default file.");
+                let default = Settings::default();
+                if let Err(e) = default.save_json() {
+                    println!("Cannot save settings: {}", e.message);
+                }
+                default
+            }
+        },
     };
 
     let settings: Arc<RwLock<Settings>> = Arc::new(RwLock::new(settings));
+    let (hotplug_tx, hotplug_rx) = channel();
     let state = State { 
         settings,
-        connected: Arc::new(RwLock::new(false)),
+        devices: Arc::new(RwLock::new(HashMap::new())),
+        hid_devices: Arc::new(RwLock::new(Vec::new())),
+        hotplug: Mutex::new(hotplug_tx.clone()),
     };
 
     // start a separate thread to listen for HID stuff.
     let cloned_settings = state.settings.clone();
-    let cloned_connected = state.connected.clone();
-    std::thread::spawn(move || hid::start_hid_thread(cloned_settings, cloned_connected));
+    let cloned_devices = state.devices.clone();
+    let cloned_hid_devices = state.hid_devices.clone();
+    std::thread::spawn(move || hid::start_hid_thread(cloned_settings, cloned_devices, cloned_hid_devices, hotplug_rx, hotplug_tx));
+    ducking::spawn_monitor(state.settings.clone());
 
     let tray_settings = state.settings.clone();
-    let mut show_stats = CustomMenuItem::new("show_stats".to_string(), "Send Stats to Macropad");
-    {
-        show_stats.selected = tray_settings.write().unwrap().show_stats;
-    }
-    let tray_menu = SystemTrayMenu::new()
-        .add_item(show_stats)
-        .add_item(CustomMenuItem::new("quit".to_string(), "Quit"));
+    let menu = tray_menu(&tray_settings.read().unwrap());
 
     tauri::Builder::default()
         .manage(state)
@@ -260,18 +555,53 @@ fn main() {
                         std::process::exit(0);
                     }
                     "show_stats" => {
+                        // the tray toggle applies to every device
                         let mut settings = tray_settings.write().unwrap();
-                        settings.show_stats = !settings.show_stats;
-                        app.tray_handle().get_item("show_stats").set_selected(settings.show_stats);
-                        settings.save_json();
+                        let show = !settings.defaults.show_stats;
+                        settings.defaults.show_stats = show;
+                        for profile in settings.profiles.values_mut() {
+                            profile.show_stats = show;
+                        }
+                        app.tray_handle().get_item("show_stats").set_selected(show);
+                        if let Err(e) = settings.save_json() {
+                            println!("Cannot save settings: {}", e.message);
+                        }
+                    }
+                    id => {
+                        if let Some(name) = id.strip_prefix("scene:") {
+                            let scene = tray_settings.read().unwrap().scenes.iter().find(|s| s.name == name).cloned();
+                            if let Some(scene) = scene {
+                                scenes::restore_in_background(scene);
+                            }
+                        }
                     }
-                    _ => {}
                 }
             }
             _ => ()
         })
-        .system_tray(SystemTray::new().with_menu(tray_menu))
-        .invoke_handler(tauri::generate_handler![get_apps, set_mapping, get_connected_state, get_process_list, open_window, set_increment, get_volume_inc])
+        .system_tray(SystemTray::new().with_menu(menu))
+        .invoke_handler(tauri::generate_handler![get_apps, set_mapping, get_connection_state, get_process_list, get_audio_devices, open_window, set_increment, get_volume_inc, get_temperature_sensors, set_temperature_sensors, list_hid_devices, set_device_match, set_show_stats, set_volume_options, save_scene, delete_scene, restore_scene, set_ducking_rules, get_diagnostics, install_udev_rule])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_mappings_from_before_profiles() {
+        let settings = Settings::from_json(r#"{"proc_list":{"192":"spotify","193":""},"show_stats":false,"increment":5}"#).unwrap();
+        assert_eq!(settings.defaults.proc_list.get(&0x00C0), Some(&Target::App("spotify".to_string())));
+        assert_eq!(settings.defaults.proc_list.get(&0x00C1), Some(&Target::default()));
+        assert!(!settings.defaults.show_stats);
+    }
+
+    #[test]
+    fn reads_what_it_saves() {
+        let mut settings = Settings::default();
+        settings.profile_mut(Some("pad")).proc_list.insert(0x00C2, Target::App("discord".to_string()));
+        let saved = serde_json::to_string(&settings).unwrap();
+        assert!(Settings::from_json(&saved).unwrap() == settings);
+    }
+}
@@ -0,0 +1,110 @@
+use serde::{Serialize, Deserialize};
+
+/// What a key controls.
+///
+/// Plain strings are application names, the only kind older configs have.
+/// Everything else is an object tagged with `kind`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Target {
+    App(String),
+    Typed(TypedTarget),
+}
+
+/// Which property of an audio session an `Application` target compares.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchBy {
+    /// File name of the process, `.exe` is optional on Windows.
+    Executable,
+    /// Full path of the process executable.
+    Path,
+    /// Regular expression tried against the executable name, path and
+    /// display name.
+    Regex,
+    /// Name the application gives its audio stream.
+    DisplayName,
+}
+
+/// How a group applies a key press to its members.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupMode {
+    /// Every member moves one step from its own level.
+    #[default]
+    Relative,
+    /// Every member is set to one step from the loudest member.
+    Normalized,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TypedTarget {
+    /// Application sessions picked by something other than the bare name.
+    Application { by: MatchBy, pattern: String },
+    /// Master volume of an output device, by id or name.
+    DeviceMaster { device: String },
+    DefaultOutput,
+    DefaultInput,
+    /// Level of an input device, by id or name.
+    CaptureDevice { device: String },
+    /// Makes the next output in `devices` the default, every active output
+    /// in system order when the list is empty.
+    CycleOutput {
+        #[serde(default)]
+        devices: Vec<String>,
+    },
+    /// Makes `device` the default output.
+    SwitchOutput { device: String },
+    /// Whatever application has the focused window when the key is pressed.
+    ForegroundApp,
+    /// Several applications on one key, like a "comms" key for every chat app.
+    Group {
+        apps: Vec<Target>,
+        #[serde(default)]
+        mode: GroupMode,
+    },
+    /// Restores the saved scene with this name on any press.
+    Scene { scene: String },
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::App(String::default())
+    }
+}
+
+impl Target {
+    pub fn is_unset(&self) -> bool {
+        matches!(self, Target::App(app) if app.is_empty())
+    }
+
+    /// Short label sent to the device display.
+    pub fn display_name(&self) -> String {
+        match self {
+            Target::App(app) => app.clone(),
+            Target::Typed(TypedTarget::Application { pattern, .. }) => pattern.clone(),
+            Target::Typed(TypedTarget::DeviceMaster { device }) => device.clone(),
+            Target::Typed(TypedTarget::DefaultOutput) => "Output".to_string(),
+            Target::Typed(TypedTarget::DefaultInput) => "Input".to_string(),
+            Target::Typed(TypedTarget::CaptureDevice { device }) => device.clone(),
+            Target::Typed(TypedTarget::CycleOutput { .. }) => "Cycle output".to_string(),
+            Target::Typed(TypedTarget::SwitchOutput { device }) => device.clone(),
+            Target::Typed(TypedTarget::ForegroundApp) => "Focused app".to_string(),
+            Target::Typed(TypedTarget::Group { apps, .. }) => apps.iter()
+                .map(|app| app.display_name())
+                .collect::<Vec<String>>()
+                .join("+"),
+            Target::Typed(TypedTarget::Scene { scene }) => scene.clone(),
+        }
+    }
+
+    /// Keys that change the default output rather than a volume.
+    pub fn switches_output(&self) -> bool {
+        matches!(self, Target::Typed(TypedTarget::CycleOutput { .. } | TypedTarget::SwitchOutput { .. }))
+    }
+
+    pub fn is_scene(&self) -> bool {
+        matches!(self, Target::Typed(TypedTarget::Scene { .. }))
+    }
+}
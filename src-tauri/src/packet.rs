@@ -1,42 +1,134 @@
+use serde::Serialize;
 use crate::error::HidError;
 
-const PACKET_INIT: u8 = 0x69;
+pub const PACKET_INIT: u8 = 0x69;
+pub const PROTOCOL_VERSION: u8 = 0x01;
+pub const REPORT_SIZE: usize = 32;
+
+// magic, magic, header, flags, fragment index, total length (u16 le)
+const FRAME_HEADER_SIZE: usize = 7;
+const FRAGMENT_DATA_SIZE: usize = REPORT_SIZE - FRAME_HEADER_SIZE;
+const FRAGMENT_FIRST: u8 = 0x01;
+const FRAGMENT_MORE: u8 = 0x02;
+pub const MAX_PAYLOAD: usize = (u8::MAX as usize + 1) * FRAGMENT_DATA_SIZE;
 
 #[repr(u8)]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum PacketHeader {
+    Hello = 0x01,
     Temperature = 0x02,
     ChangeVolume = 0x04,
     GetVolume = 0x05,
     ForceVolume = 0x06,
     Stats = 0x08,
+    OutputDevice = 0x09,
+    Ack = 0x0A,
+    Nack = 0x0B,
     Unknown = 0xFF,
 }
 
 impl PacketHeader {
     pub fn from_u8(byte: u8) -> PacketHeader {
         match byte {
+            0x01 => PacketHeader::Hello,
             0x02 => PacketHeader::Temperature,
             0x04 => PacketHeader::ChangeVolume,
             0x05 => PacketHeader::GetVolume,
             0x06 => PacketHeader::ForceVolume,
             0x08 => PacketHeader::Stats,
+            0x09 => PacketHeader::OutputDevice,
+            0x0A => PacketHeader::Ack,
+            0x0B => PacketHeader::Nack,
             _ => PacketHeader::Unknown
         }
     }
 
     pub fn into_u8(&self) -> u8 {
         match self {
+            PacketHeader::Hello => 0x01,
             PacketHeader::Temperature => 0x02,
             PacketHeader::ChangeVolume => 0x04,
             PacketHeader::GetVolume => 0x05,
             PacketHeader::ForceVolume => 0x06,
             PacketHeader::Stats => 0x08,
+            PacketHeader::OutputDevice => 0x09,
+            PacketHeader::Ack => 0x0A,
+            PacketHeader::Nack => 0x0B,
             PacketHeader::Unknown => 0xFF,
         }
     }
 }
 
+/// Feature bitmap reported by the firmware in its `Hello` reply.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const STATS: u8 = 1 << 0;
+    pub const TEMPERATURE: u8 = 1 << 1;
+    pub const DISPLAY: u8 = 1 << 2;
+    pub const ENCODER: u8 = 1 << 3;
+    pub const LEDS: u8 = 1 << 4;
+    pub const FRAGMENTS: u8 = 1 << 5;
+    pub const RELIABLE: u8 = 1 << 6;
+
+    pub fn from_bits(bits: u8) -> Self {
+        Capabilities(bits)
+    }
+
+    /// What firmware from before the handshake existed understands.
+    pub fn legacy() -> Self {
+        Capabilities(Self::STATS | Self::ENCODER)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn supports(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FirmwareInfo {
+    pub firmware_version: (u8, u8, u8),
+    pub protocol_version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl FirmwareInfo {
+    /// Used when the device never answers our `Hello`.
+    pub fn legacy() -> Self {
+        Self {
+            firmware_version: (0, 0, 0),
+            protocol_version: 0,
+            capabilities: Capabilities::legacy(),
+        }
+    }
+}
+
+/// How packets are laid out in HID reports.
+///
+/// `Legacy` is one report per packet, truncated to whatever fits. `Fragmented`
+/// prefixes every report with flags, a fragment index and the total payload
+/// length so a payload can span several reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    Legacy,
+    Fragmented,
+}
+
+impl Framing {
+    pub fn for_capabilities(capabilities: &Capabilities) -> Self {
+        if capabilities.supports(Capabilities::FRAGMENTS) {
+            Framing::Fragmented
+        } else {
+            Framing::Legacy
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Packet {
     pub header: PacketHeader,
@@ -45,17 +137,53 @@ pub struct Packet {
 
 impl Packet {
     pub fn new(header: PacketHeader, data: Vec<u8>) -> Self {
-        let mut sized_vec = vec![0 as u8; 32];
-        let mut i = 0;
-        for item in data {
-            sized_vec[i] = item;
-            i += 1;
-        }
-
         Self {
             header,
-            data: sized_vec,
+            data,
+        }
+    }
+
+    pub fn ack(sequence: u8) -> Self {
+        Packet::new(PacketHeader::Ack, vec![sequence])
+    }
+
+    pub fn nack(sequence: u8) -> Self {
+        Packet::new(PacketHeader::Nack, vec![sequence])
+    }
+
+    /// Sequence number of an ack, nack or sealed packet.
+    pub fn sequence(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// Wraps the payload for reliable delivery: `[seq] payload crc16(le)`.
+    /// The checksum covers the header byte, the sequence number and the payload.
+    pub fn seal(&self, sequence: u8) -> Packet {
+        let mut data = Vec::with_capacity(self.data.len() + 3);
+        data.push(sequence);
+        data.extend_from_slice(&self.data);
+        let mut checked = vec![self.header.into_u8()];
+        checked.extend_from_slice(&data);
+        data.extend_from_slice(&crc16(&checked).to_le_bytes());
+
+        Packet::new(self.header, data)
+    }
+
+    /// Reverses `seal`, returning the sequence number and the original packet.
+    pub fn unseal(self) -> Result<(u8, Packet), HidError> {
+        if self.data.len() < 3 {
+            return Err(HidError::new("Sealed packet is too short!".to_string()));
         }
+        let split = self.data.len() - 2;
+        let expected = u16::from_le_bytes([self.data[split], self.data[split + 1]]);
+        let mut checked = vec![self.header.into_u8()];
+        checked.extend_from_slice(&self.data[..split]);
+        let actual = crc16(&checked);
+        if actual != expected {
+            return Err(HidError::new(format!("Checksum mismatch, expected {:#06x} got {:#06x}!", expected, actual)));
+        }
+
+        Ok((self.data[0], Packet::new(self.header, self.data[1..split].to_vec())))
     }
 
     pub fn from(received: usize, data: &[u8]) -> Result<Self, HidError> {
@@ -89,4 +217,425 @@ impl Packet {
 
         vec_clone
     }
-}
\ No newline at end of file
+
+    pub fn to_reports(&self, framing: Framing) -> Result<Vec<Vec<u8>>, HidError> {
+        if framing == Framing::Legacy {
+            let mut report = self.to_packet_bytes();
+            report.resize(REPORT_SIZE, 0);
+            return Ok(vec![report]);
+        }
+
+        if self.data.len() > MAX_PAYLOAD {
+            return Err(HidError::new(format!("Packet payload of {} bytes is too large!", self.data.len())));
+        }
+        let total = (self.data.len() as u16).to_le_bytes();
+        let chunks: Vec<&[u8]> = if self.data.is_empty() {
+            vec![&[]]
+        } else {
+            self.data.chunks(FRAGMENT_DATA_SIZE).collect()
+        };
+
+        let last = chunks.len() - 1;
+        let reports = chunks.iter().enumerate().map(|(index, chunk)| {
+            let mut flags = 0;
+            if index == 0 {
+                flags |= FRAGMENT_FIRST;
+            }
+            if index < last {
+                flags |= FRAGMENT_MORE;
+            }
+            let mut report = vec![PACKET_INIT, PACKET_INIT, self.header.into_u8(), flags, index as u8, total[0], total[1]];
+            report.extend_from_slice(chunk);
+            report.resize(REPORT_SIZE, 0);
+            report
+        }).collect();
+
+        Ok(reports)
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Down = 0x00,
+    Up = 0x01,
+}
+
+impl Direction {
+    pub fn from_u8(byte: u8) -> Direction {
+        if byte == Direction::Up as u8 {
+            Direction::Up
+        } else {
+            Direction::Down
+        }
+    }
+}
+
+pub trait Encode {
+    fn encode(&self) -> Packet;
+}
+
+pub trait Decode: Sized {
+    fn decode(packet: &Packet) -> Result<Self, HidError>;
+}
+
+/// Messages sent by the device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceMessage {
+    Hello(FirmwareInfo),
+    GetVolume { key: u16 },
+    ChangeVolume { key: u16, dir: Direction },
+    ForceVolume { key: u16, dir: Direction },
+}
+
+/// Messages sent to the device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostMessage {
+    Hello { protocol_version: u8 },
+    /// `volume` is a percentage, `None` when the application isn't running.
+    Volume { volume: Option<u8>, name: String },
+    Stats { cpu: u8, mem: u8, procs: u16, gpu: u8 },
+    /// Tenths of a degree per configured sensor, `None` when it can't be read.
+    Temperature { readings: Vec<Option<i16>> },
+    /// Name of the default output after a key switched it.
+    OutputDevice { name: String },
+}
+
+// volume byte for an application that isn't running
+const NO_VOLUME: u8 = 0xFF;
+// temperature reading for a sensor that can't be found
+const NO_READING: i16 = i16::MIN;
+
+fn expect_len(packet: &Packet, len: usize) -> Result<&[u8], HidError> {
+    if packet.data.len() < len {
+        return Err(HidError::new(format!("{:?} packet needs {} bytes, got {}!", packet.header, len, packet.data.len())));
+    }
+    Ok(&packet.data)
+}
+
+fn key_from(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+impl Encode for DeviceMessage {
+    fn encode(&self) -> Packet {
+        match self {
+            DeviceMessage::Hello(firmware) => {
+                let (major, minor, patch) = firmware.firmware_version;
+                let data = vec![major, minor, patch, firmware.protocol_version, firmware.capabilities.bits()];
+                Packet::new(PacketHeader::Hello, data)
+            }
+            DeviceMessage::GetVolume { key } => Packet::new(PacketHeader::GetVolume, key.to_le_bytes().to_vec()),
+            DeviceMessage::ChangeVolume { key, dir } | DeviceMessage::ForceVolume { key, dir } => {
+                let header = match self {
+                    DeviceMessage::ForceVolume { .. } => PacketHeader::ForceVolume,
+                    _ => PacketHeader::ChangeVolume,
+                };
+                let mut data = key.to_le_bytes().to_vec();
+                data.push(*dir as u8);
+                Packet::new(header, data)
+            }
+        }
+    }
+}
+
+impl Decode for DeviceMessage {
+    fn decode(packet: &Packet) -> Result<Self, HidError> {
+        match packet.header {
+            PacketHeader::Hello => {
+                let data = expect_len(packet, 5)?;
+                Ok(DeviceMessage::Hello(FirmwareInfo {
+                    firmware_version: (data[0], data[1], data[2]),
+                    protocol_version: data[3],
+                    capabilities: Capabilities::from_bits(data[4]),
+                }))
+            }
+            PacketHeader::GetVolume => {
+                let data = expect_len(packet, 2)?;
+                Ok(DeviceMessage::GetVolume { key: key_from(data) })
+            }
+            PacketHeader::ChangeVolume => {
+                let data = expect_len(packet, 3)?;
+                Ok(DeviceMessage::ChangeVolume { key: key_from(data), dir: Direction::from_u8(data[2]) })
+            }
+            PacketHeader::ForceVolume => {
+                let data = expect_len(packet, 3)?;
+                Ok(DeviceMessage::ForceVolume { key: key_from(data), dir: Direction::from_u8(data[2]) })
+            }
+            header => Err(HidError::new(format!("{:?} is not sent by the device!", header)))
+        }
+    }
+}
+
+impl Encode for HostMessage {
+    fn encode(&self) -> Packet {
+        match self {
+            HostMessage::Hello { protocol_version } => Packet::new(PacketHeader::Hello, vec![*protocol_version]),
+            HostMessage::Volume { volume, name } => {
+                let mut data = vec![volume.unwrap_or(NO_VOLUME)];
+                data.extend_from_slice(name.as_bytes());
+                Packet::new(PacketHeader::GetVolume, data)
+            }
+            HostMessage::Stats { cpu, mem, procs, gpu } => {
+                let procs = procs.to_be_bytes();
+                Packet::new(PacketHeader::Stats, vec![*cpu, *mem, procs[0], procs[1], *gpu])
+            }
+            HostMessage::Temperature { readings } => {
+                let mut data = vec![readings.len() as u8];
+                for reading in readings {
+                    data.extend_from_slice(&reading.unwrap_or(NO_READING).to_le_bytes());
+                }
+                Packet::new(PacketHeader::Temperature, data)
+            }
+            HostMessage::OutputDevice { name } => Packet::new(PacketHeader::OutputDevice, name.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl Decode for HostMessage {
+    fn decode(packet: &Packet) -> Result<Self, HidError> {
+        match packet.header {
+            PacketHeader::Hello => {
+                let data = expect_len(packet, 1)?;
+                Ok(HostMessage::Hello { protocol_version: data[0] })
+            }
+            PacketHeader::GetVolume => {
+                let data = expect_len(packet, 1)?;
+                let volume = if data[0] == NO_VOLUME { None } else { Some(data[0]) };
+                let name = String::from_utf8_lossy(&data[1..]).trim_end_matches('\0').to_string();
+                Ok(HostMessage::Volume { volume, name })
+            }
+            PacketHeader::Stats => {
+                let data = expect_len(packet, 5)?;
+                Ok(HostMessage::Stats {
+                    cpu: data[0],
+                    mem: data[1],
+                    procs: u16::from_be_bytes([data[2], data[3]]),
+                    gpu: data[4],
+                })
+            }
+            PacketHeader::Temperature => {
+                let count = expect_len(packet, 1)?[0] as usize;
+                let data = expect_len(packet, 1 + count * 2)?;
+                let readings = data[1..1 + count * 2].chunks(2).map(|c| {
+                    let reading = i16::from_le_bytes([c[0], c[1]]);
+                    if reading == NO_READING { None } else { Some(reading) }
+                }).collect();
+                Ok(HostMessage::Temperature { readings })
+            }
+            PacketHeader::OutputDevice => {
+                let name = String::from_utf8_lossy(&packet.data).trim_end_matches('\0').to_string();
+                Ok(HostMessage::OutputDevice { name })
+            }
+            header => Err(HidError::new(format!("{:?} is not sent by the host!", header)))
+        }
+    }
+}
+
+/// Header of a raw report in either framing, for logging.
+pub fn header_of(report: &[u8]) -> PacketHeader {
+    if report.len() > 2 && report[0] == PACKET_INIT && report[1] == PACKET_INIT {
+        PacketHeader::from_u8(report[2])
+    } else {
+        report.first().map(|b| PacketHeader::from_u8(*b)).unwrap_or(PacketHeader::Unknown)
+    }
+}
+
+/// CRC-16/CCITT-FALSE, the same variant the firmware uses.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+struct PartialPacket {
+    header: PacketHeader,
+    total: usize,
+    next_index: u8,
+    data: Vec<u8>,
+}
+
+/// Collects inbound reports until a whole packet has arrived.
+pub struct Reassembler {
+    framing: Framing,
+    pending: Option<PartialPacket>,
+}
+
+impl Reassembler {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            pending: None,
+        }
+    }
+
+    pub fn push(&mut self, report: &[u8]) -> Result<Option<Packet>, HidError> {
+        if self.framing == Framing::Legacy {
+            return Packet::from(report.len(), report).map(Some);
+        }
+
+        if report.len() < FRAME_HEADER_SIZE || report[0] != PACKET_INIT || report[1] != PACKET_INIT {
+            return Err(HidError::new("Report is missing packet magic!".to_string()));
+        }
+        let header = PacketHeader::from_u8(report[2]);
+        if header == PacketHeader::Unknown {
+            return Err(HidError::new("Packet header is unknown!".to_string()));
+        }
+        let flags = report[3];
+        let index = report[4];
+        let total = u16::from_le_bytes([report[5], report[6]]) as usize;
+
+        if flags & FRAGMENT_FIRST != 0 {
+            if self.pending.is_some() {
+                println!("Dropping incomplete packet, new one started.");
+            }
+            self.pending = Some(PartialPacket {
+                header,
+                total,
+                next_index: 0,
+                data: Vec::with_capacity(total),
+            });
+        }
+
+        let in_sequence = matches!(&self.pending, Some(p) if p.header == header && p.next_index == index);
+        if !in_sequence {
+            self.pending = None;
+            return Err(HidError::new(format!("Unexpected fragment {} for {:?}!", index, header)));
+        }
+        let partial = self.pending.as_mut().unwrap();
+        let chunk = &report[FRAME_HEADER_SIZE..];
+        let remaining = partial.total - partial.data.len();
+        partial.data.extend_from_slice(&chunk[..remaining.min(chunk.len())]);
+        partial.next_index = partial.next_index.wrapping_add(1);
+
+        if flags & FRAGMENT_MORE != 0 {
+            return Ok(None);
+        }
+
+        let partial = self.pending.take().unwrap();
+        if partial.data.len() != partial.total {
+            return Err(HidError::new(format!("Packet ended after {} of {} bytes!", partial.data.len(), partial.total)));
+        }
+        Ok(Some(Packet {
+            header: partial.header,
+            data: partial.data,
+        }))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_over(packet: &Packet, framing: Framing) -> Packet {
+        let mut reassembler = Reassembler::new(framing);
+        let mut received = None;
+        for report in packet.to_reports(framing).unwrap() {
+            assert_eq!(report.len(), REPORT_SIZE);
+            assert!(received.is_none(), "packet ended before its last report");
+            received = reassembler.push(&report).unwrap();
+        }
+        received.expect("packet never completed")
+    }
+
+    #[test]
+    fn device_messages_round_trip() {
+        let messages = [
+            DeviceMessage::Hello(FirmwareInfo {
+                firmware_version: (1, 2, 3),
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: Capabilities::from_bits(Capabilities::FRAGMENTS | Capabilities::RELIABLE),
+            }),
+            DeviceMessage::GetVolume { key: 0x00C3 },
+            DeviceMessage::ChangeVolume { key: 0x00C4, dir: Direction::Up },
+            DeviceMessage::ForceVolume { key: 0x1234, dir: Direction::Down },
+        ];
+        for message in messages {
+            let packet = send_over(&message.encode(), Framing::Fragmented);
+            assert_eq!(DeviceMessage::decode(&packet).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn host_messages_round_trip() {
+        let messages = [
+            HostMessage::Hello { protocol_version: PROTOCOL_VERSION },
+            HostMessage::Volume { volume: Some(42), name: "spotify".to_string() },
+            HostMessage::Volume { volume: None, name: "discord".to_string() },
+            HostMessage::Stats { cpu: 12, mem: 34, procs: 567, gpu: 89 },
+            HostMessage::Temperature { readings: vec![Some(455), None, Some(-12)] },
+            HostMessage::OutputDevice { name: "Headphones".to_string() },
+        ];
+        for message in messages {
+            let packet = send_over(&message.encode(), Framing::Fragmented);
+            assert_eq!(HostMessage::decode(&packet).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn empty_payload_is_one_report() {
+        let packet = Packet::new(PacketHeader::GetVolume, vec![]);
+        assert_eq!(packet.to_reports(Framing::Fragmented).unwrap().len(), 1);
+        let received = send_over(&packet, Framing::Fragmented);
+        assert_eq!(received.header, PacketHeader::GetVolume);
+        assert!(received.raw().is_empty());
+    }
+
+    #[test]
+    fn largest_payload_spans_every_fragment() {
+        let data: Vec<u8> = (0..MAX_PAYLOAD).map(|i| i as u8).collect();
+        assert_eq!(data.len(), 6400);
+        let packet = Packet::new(PacketHeader::GetVolume, data.clone());
+        assert_eq!(packet.to_reports(Framing::Fragmented).unwrap().len(), 256);
+        assert_eq!(send_over(&packet, Framing::Fragmented).raw(), data);
+
+        let too_large = Packet::new(PacketHeader::GetVolume, vec![0; MAX_PAYLOAD + 1]);
+        assert!(too_large.to_reports(Framing::Fragmented).is_err());
+    }
+
+    #[test]
+    fn missing_fragment_is_rejected() {
+        let packet = Packet::new(PacketHeader::GetVolume, vec![7; FRAGMENT_DATA_SIZE * 3]);
+        let reports = packet.to_reports(Framing::Fragmented).unwrap();
+        let mut reassembler = Reassembler::new(Framing::Fragmented);
+        assert!(reassembler.push(&reports[0]).unwrap().is_none());
+        assert!(reassembler.push(&reports[2]).is_err());
+        // the next packet starts over cleanly
+        assert_eq!(send_over(&packet, Framing::Fragmented).raw(), packet.raw());
+    }
+
+    #[test]
+    fn sealed_packets_round_trip() {
+        let message = HostMessage::Stats { cpu: 1, mem: 2, procs: 3, gpu: 4 };
+        let sealed = send_over(&message.encode().seal(17), Framing::Fragmented);
+        assert_eq!(sealed.sequence(), Some(17));
+        let (sequence, packet) = sealed.unseal().unwrap();
+        assert_eq!(sequence, 17);
+        assert_eq!(HostMessage::decode(&packet).unwrap(), message);
+    }
+
+    #[test]
+    fn corrupted_checksum_is_rejected() {
+        let sealed = DeviceMessage::GetVolume { key: 0x00C0 }.encode().seal(3);
+        let mut data = sealed.raw();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        assert!(Packet::new(sealed.header, data).unseal().is_err());
+
+        let mut data = sealed.raw();
+        data[1] ^= 0x80;
+        assert!(Packet::new(sealed.header, data).unseal().is_err());
+
+        // the header byte is covered too
+        assert!(Packet::new(PacketHeader::ChangeVolume, sealed.raw()).unseal().is_err());
+        assert!(Packet::new(sealed.header, vec![3, 0]).unseal().is_err());
+    }
+}
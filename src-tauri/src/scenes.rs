@@ -0,0 +1,124 @@
+use std::thread::sleep;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use crate::audio::{self, AudioBackend, Control};
+use crate::mapping::Target;
+
+// a fade sets every volume this often
+const FADE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Level of one target when its scene was saved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneEntry {
+    pub target: Target,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+/// Named set of levels put back all at once, like "meeting" or "gaming".
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub name: String,
+    pub entries: Vec<SceneEntry>,
+    /// Milliseconds to fade to the saved levels over, 0 jumps straight there.
+    #[serde(default)]
+    pub fade_ms: u64,
+}
+
+/// What a snapshot covers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotScope {
+    /// Every application with an audio session right now.
+    Sessions,
+    /// Every mapped key that controls a volume, devices included.
+    Mapped,
+}
+
+/// Current levels of everything in `scope`. `mapped` are the targets of the
+/// profile the scene is saved from.
+pub fn snapshot(audio: &dyn AudioBackend, scope: SnapshotScope, mapped: &[Target]) -> Result<Vec<SceneEntry>, anyhow::Error> {
+    let mut entries: Vec<SceneEntry> = Vec::new();
+    match scope {
+        SnapshotScope::Sessions => {
+            for session in audio.sessions()? {
+                // same naming as mappings, so the entry finds every session of the app again
+                let target = Target::App(audio::executable_stem(&session.name));
+                if !entries.iter().any(|e| e.target == target) {
+                    entries.push(SceneEntry { target, volume: session.volume, muted: session.muted });
+                }
+            }
+        }
+        SnapshotScope::Mapped => {
+            for target in mapped {
+                if target.is_unset() || target.switches_output() || target.is_scene() || entries.iter().any(|e| e.target == *target) {
+                    continue;
+                }
+                let controls = audio::resolve(audio, target)?;
+                if let Some(volume) = controls.iter().map(|c| c.volume()).reduce(f32::max) {
+                    let muted = controls.iter().all(|c| c.muted());
+                    entries.push(SceneEntry { target: target.clone(), volume, muted });
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Puts every entry of `scene` back, skipping applications that aren't
+/// running. Blocks for the length of the fade.
+pub fn restore(audio: &dyn AudioBackend, scene: &Scene) -> Result<(), anyhow::Error> {
+    let mut fades: Vec<(Control, &SceneEntry)> = Vec::new();
+    for entry in &scene.entries {
+        match audio::resolve(audio, &entry.target) {
+            Ok(controls) => fades.extend(controls.into_iter().map(|c| (c, entry))),
+            Err(e) => println!("Cannot restore {:?}: {}", entry.target, e),
+        }
+    }
+
+    // a control that fails, usually because its app just quit, is left out
+    // of the rest of the fade instead of ending it for the others
+    let applied = |control: &Control, result: Result<(), anyhow::Error>| match result {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Cannot restore {}: {}", control.name(), e);
+            false
+        }
+    };
+
+    // unmute first so the fade is heard, mute only once it's done
+    fades.retain(|(control, entry)| {
+        if control.muted() && !entry.muted {
+            return applied(control, control.set_mute(audio, false));
+        }
+        true
+    });
+    let steps = (scene.fade_ms / FADE_INTERVAL.as_millis() as u64).max(1);
+    for step in 1..=steps {
+        let progress = step as f32 / steps as f32;
+        fades.retain(|(control, entry)| {
+            let from = control.level();
+            applied(control, control.set_volume(audio, from + (entry.volume - from) * progress))
+        });
+        if step < steps {
+            sleep(FADE_INTERVAL);
+        }
+    }
+    for (control, entry) in &fades {
+        if entry.muted && !control.muted() {
+            applied(control, control.set_mute(audio, true));
+        }
+    }
+    println!("Restored scene {}", scene.name);
+    Ok(())
+}
+
+/// Restores `scene` on its own thread with its own backend, so the fade
+/// holds up neither the tray and UI nor the device loop.
+pub fn restore_in_background(scene: Scene) {
+    std::thread::spawn(move || {
+        if let Err(e) = audio::platform_backend().and_then(|audio| restore(&*audio, &scene)) {
+            println!("Cannot restore scene {}: {}", scene.name, e);
+        }
+    });
+}
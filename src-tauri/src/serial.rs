@@ -0,0 +1,250 @@
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use serialport::SerialPort;
+use crate::error::HidError;
+use crate::packet::{PACKET_INIT, REPORT_SIZE};
+use crate::transport::{Transport, TransportInfo};
+
+// stands in for "block forever", which serialport has no setting for
+const BLOCKING_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[cfg(target_os = "linux")]
+const BY_ID_DIR: &str = "/dev/serial/by-id";
+
+/// Talking to the pad over a USB serial port instead of raw HID.
+///
+/// With no `path` the port is found under `/dev/serial/by-id` using the
+/// vendor and product id from the device match rule. Any tty works as a
+/// path, including one end of a pty pair for local testing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SerialSettings {
+    pub enabled: bool,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+}
+
+fn default_baud_rate() -> u32 {
+    115200
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            baud_rate: default_baud_rate(),
+        }
+    }
+}
+
+/// Ports under `/dev/serial/by-id` belonging to the given USB vendor and product.
+#[cfg(target_os = "linux")]
+pub fn discover(vendor_id: u16, product_id: u16) -> Vec<String> {
+    let entries = match std::fs::read_dir(BY_ID_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut ports = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let tty = match std::fs::canonicalize(&path) {
+            Ok(tty) => tty,
+            Err(_) => continue,
+        };
+        let name = match tty.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        // /sys/class/tty/ttyACM0/device is the usb interface, its parent holds the ids
+        let interface = match std::fs::canonicalize(format!("/sys/class/tty/{}/device", name)) {
+            Ok(interface) => interface,
+            Err(_) => continue,
+        };
+        let usb_device = match interface.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => continue,
+        };
+        let read_id = |file: &str| {
+            std::fs::read_to_string(usb_device.join(file)).ok()
+                .and_then(|id| u16::from_str_radix(id.trim(), 16).ok())
+        };
+        if read_id("idVendor") == Some(vendor_id) && read_id("idProduct") == Some(product_id) {
+            ports.push(path.to_string_lossy().to_string());
+        }
+    }
+    ports
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn discover(_vendor_id: u16, _product_id: u16) -> Vec<String> {
+    vec![]
+}
+
+/// Carries the same fixed size reports as HID over a byte stream. Partial
+/// reports left over from a timed out read are kept for the next one, and a
+/// report that doesn't start with the packet magic but has it further in is
+/// realigned to it, so one lost byte doesn't shift every report after it.
+///
+/// Reads and writes go through separate handles to the port so a blocked
+/// read never holds up a write.
+pub struct SerialTransport {
+    reader: Mutex<Box<dyn SerialPort>>,
+    writer: Mutex<Box<dyn SerialPort>>,
+    pending: Mutex<Vec<u8>>,
+    info: TransportInfo,
+    closed: AtomicBool,
+}
+
+impl SerialTransport {
+    pub fn open(path: &str, baud_rate: u32, vendor_id: u16, product_id: u16) -> Result<Self, HidError> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(Duration::from_millis(500))
+            .open()?;
+        let writer = port.try_clone()?;
+        Ok(Self {
+            reader: Mutex::new(port),
+            writer: Mutex::new(writer),
+            pending: Mutex::new(Vec::with_capacity(REPORT_SIZE)),
+            info: TransportInfo {
+                path: path.to_string(),
+                vendor_id,
+                product_id,
+                serial_number: None,
+                product: Some("Serial".to_string()),
+            },
+            closed: AtomicBool::new(false),
+        })
+    }
+}
+
+/// Where the next report starts when `report` is misaligned. Legacy reports
+/// carry no magic at all and are taken as they are.
+fn resync_offset(report: &[u8]) -> Option<usize> {
+    // the byte after the magic is a header, never the magic again; a start cut
+    // off by the end of the report counts, the rest is read next
+    let starts_here = |i: usize| {
+        let bytes = &report[i..];
+        bytes[0] == PACKET_INIT
+            && bytes.get(1).map_or(true, |b| *b == PACKET_INIT)
+            && bytes.get(2).map_or(true, |b| *b != PACKET_INIT)
+    };
+    let start = (0..report.len()).find(|i| starts_here(*i))?;
+    (start > 0).then_some(start)
+}
+
+impl Transport for SerialTransport {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(HidError::new("Serial port is closed!".to_string()));
+        }
+        let mut port = self.reader.lock().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+        let timeout = if timeout < 0 {
+            BLOCKING_TIMEOUT
+        } else {
+            Duration::from_millis(timeout as u64)
+        };
+        port.set_timeout(timeout)?;
+
+        let mut chunk = [0u8; REPORT_SIZE];
+        loop {
+            while pending.len() < REPORT_SIZE {
+                match port.read(&mut chunk[..REPORT_SIZE - pending.len()]) {
+                    Ok(0) => return Err(HidError::new("Serial port closed by the device!".to_string())),
+                    Ok(read) => pending.extend_from_slice(&chunk[..read]),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => return Ok(0),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            match resync_offset(&pending) {
+                Some(skip) => {
+                    println!("Serial stream out of sync, skipping {} bytes.", skip);
+                    pending.drain(..skip);
+                }
+                None => break,
+            }
+        }
+
+        let len = REPORT_SIZE.min(buf.len());
+        buf[..len].copy_from_slice(&pending[..len]);
+        pending.clear();
+        Ok(len)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(HidError::new("Serial port is closed!".to_string()));
+        }
+        let mut report = data.to_vec();
+        report.resize(REPORT_SIZE, 0);
+        let mut port = self.writer.lock().unwrap();
+        port.write_all(&report)?;
+        port.flush()?;
+        Ok(data.len())
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn info(&self) -> TransportInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Framing, Packet, PacketHeader};
+
+    #[test]
+    fn finds_the_next_report() {
+        let report = Packet::new(PacketHeader::OutputDevice, b"Speakers".to_vec()).to_reports(Framing::Fragmented).unwrap().remove(0);
+        assert_eq!(resync_offset(&report), None);
+        assert_eq!(resync_offset(&report[3..]), None);
+        assert_eq!(resync_offset(&[&[0x00, PACKET_INIT, 0x04][..], &report].concat()), Some(3));
+        // legacy reports start with the header
+        assert_eq!(resync_offset(&[0x04, 0xC0, 0x00, 0x01]), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resyncs_over_a_pty() {
+        let (mut pad, host_end) = serialport::TTYPort::pair().unwrap();
+        let transport = SerialTransport::open(&host_end.name().unwrap(), 115200, 0, 0).unwrap();
+        let reports = Packet::new(PacketHeader::OutputDevice, vec![0x42; 40]).to_reports(Framing::Fragmented).unwrap();
+
+        // line noise before the first report and a byte lost from the second
+        pad.write_all(&[0xFF, 0x00, PACKET_INIT]).unwrap();
+        pad.write_all(&reports[0]).unwrap();
+        pad.write_all(&reports[1][1..]).unwrap();
+        pad.write_all(&reports[0]).unwrap();
+        pad.write_all(&reports[1][..10]).unwrap();
+        pad.flush().unwrap();
+
+        let mut buf = [0u8; REPORT_SIZE];
+        assert_eq!(transport.read_timeout(&mut buf, 1000).unwrap(), REPORT_SIZE);
+        assert_eq!(buf[..], reports[0][..]);
+        // the damaged report is skipped up to the magic of the one after it
+        assert_eq!(transport.read_timeout(&mut buf, 1000).unwrap(), REPORT_SIZE);
+        assert_eq!(buf[..], reports[0][..]);
+        // half a report times out and is finished by the next read
+        assert_eq!(transport.read_timeout(&mut buf, 100).unwrap(), 0);
+        pad.write_all(&reports[1][10..]).unwrap();
+        assert_eq!(transport.read_timeout(&mut buf, 1000).unwrap(), REPORT_SIZE);
+        assert_eq!(buf[..], reports[1][..]);
+
+        transport.write(&reports[0][..10]).unwrap();
+        let mut written = [0u8; REPORT_SIZE];
+        pad.read_exact(&mut written).unwrap();
+        assert_eq!(written[..10], reports[0][..10]);
+        assert!(written[10..].iter().all(|b| *b == 0));
+    }
+}
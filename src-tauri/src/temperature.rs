@@ -0,0 +1,57 @@
+use nvml_wrapper::Device;
+use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+use sysinfo::{Component, ComponentExt, System, SystemExt};
+
+/// Read from NVML rather than `sysinfo`, which doesn't see the GPU die.
+pub const GPU_SENSOR: &str = "gpu";
+pub const CPU_PACKAGE_SENSOR: &str = "cpu package";
+
+// what the cpu package sensor is called by the various drivers (coretemp, k10temp, wmi)
+const CPU_PACKAGE_LABELS: [&str; 4] = ["package id", "tctl", "cpu package", "cpu"];
+
+pub fn default_sensors() -> Vec<String> {
+    vec!["CPU Package".to_string(), "GPU".to_string()]
+}
+
+/// Labels that can be put in `Settings.temperature_sensors`.
+pub fn available_sensors(sys: &mut System, gpu: Option<&Device>) -> Vec<String> {
+    sys.refresh_components_list();
+    let mut sensors = vec!["CPU Package".to_string()];
+    if gpu.is_some() {
+        sensors.push("GPU".to_string());
+    }
+    for component in sys.components() {
+        sensors.push(component.label().to_string());
+    }
+    sensors
+}
+
+/// First component whose label is one of `labels`, or failing that contains
+/// one, so "Core 1" doesn't pick up "Core 10". `labels` are lowercase and
+/// tried in order.
+fn find_component<'a>(components: &'a [Component], labels: &[&str]) -> Option<&'a Component> {
+    let exact = labels.iter().find_map(|label| components.iter().find(|c| c.label().to_lowercase() == *label));
+    exact.or_else(|| labels.iter().find_map(|label| components.iter().find(|c| c.label().to_lowercase().contains(label))))
+}
+
+pub fn read_temperature(sys: &System, gpu: Option<&Device>, label: &str) -> Option<f32> {
+    let label = label.to_lowercase();
+    if label == GPU_SENSOR {
+        return gpu.and_then(|gpu| gpu.temperature(TemperatureSensor::Gpu).ok()).map(|t| t as f32);
+    }
+
+    let components = sys.components();
+    let found = if label == CPU_PACKAGE_SENSOR {
+        find_component(components, &CPU_PACKAGE_LABELS)
+    } else {
+        find_component(components, &[&label])
+    };
+    found.map(|c| c.temperature())
+}
+
+/// Readings in tenths of a degree, in the order the sensors are configured.
+pub fn read_temperatures(sys: &System, gpu: Option<&Device>, sensors: &[String]) -> Vec<Option<i16>> {
+    sensors.iter()
+        .map(|sensor| read_temperature(sys, gpu, sensor).map(|celsius| (celsius * 10.0).round() as i16))
+        .collect()
+}
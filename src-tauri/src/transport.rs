@@ -0,0 +1,203 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use hidapi::{DeviceInfo, HidApi, HidDevice};
+use serde::Serialize;
+use crate::error::HidError;
+
+/// Describes whatever is on the other end of a transport.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TransportInfo {
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+}
+
+/// Something reports can be read from and written to.
+///
+/// `read_timeout` returns `Ok(0)` when nothing arrived in time and an error
+/// only when the device is gone. A timeout below zero blocks. Reads and
+/// writes may come from different threads, a blocked read must not hold up
+/// a write.
+pub trait Transport: Send + Sync {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError>;
+    fn write(&self, data: &[u8]) -> Result<usize, HidError>;
+    fn close(&self);
+    fn info(&self) -> TransportInfo;
+}
+
+// how long a read may hold a shared handle before letting a write through
+const SHARED_READ_SLICE: i32 = 5;
+
+/// Opens the device twice so the reader blocking on one handle never holds
+/// up the writer on the other.
+///
+/// macOS and some Windows drivers only allow one handle. Both sides share it
+/// then, and reads block in short slices so writes get a turn in between.
+pub struct HidTransport {
+    reader: Mutex<HidDevice>,
+    writer: Option<Mutex<HidDevice>>,
+    info: TransportInfo,
+    closed: AtomicBool,
+}
+
+impl HidTransport {
+    pub fn open(api: &HidApi, device_info: &DeviceInfo) -> Result<Self, HidError> {
+        let reader = device_info.open_device(api)?;
+        let writer = match device_info.open_device(api) {
+            Ok(writer) => Some(Mutex::new(writer)),
+            Err(e) => {
+                println!("Cannot open a second handle, sharing one: {}", e);
+                None
+            }
+        };
+        let info = TransportInfo {
+            path: device_info.path().to_string_lossy().to_string(),
+            vendor_id: device_info.vendor_id(),
+            product_id: device_info.product_id(),
+            serial_number: device_info.serial_number().map(|s| s.to_string()),
+            product: device_info.product_string().map(|s| s.to_string()),
+        };
+        Ok(Self {
+            reader: Mutex::new(reader),
+            writer,
+            info,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    fn check_open(&self) -> Result<(), HidError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(HidError::new("Device is closed!".to_string()));
+        }
+        Ok(())
+    }
+
+    fn read_shared(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        let deadline = (timeout >= 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+        loop {
+            self.check_open()?;
+            let slice = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_millis().min(SHARED_READ_SLICE as u128) as i32,
+                None => SHARED_READ_SLICE,
+            };
+            let read = self.reader.lock().unwrap().read_timeout(buf, slice)?;
+            if read > 0 || slice < SHARED_READ_SLICE {
+                return Ok(read);
+            }
+        }
+    }
+}
+
+impl Transport for HidTransport {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        self.check_open()?;
+        if self.writer.is_none() {
+            return self.read_shared(buf, timeout);
+        }
+        Ok(self.reader.lock().unwrap().read_timeout(buf, timeout)?)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        self.check_open()?;
+        let writer = self.writer.as_ref().unwrap_or(&self.reader);
+        Ok(writer.lock().unwrap().write(data)?)
+    }
+
+    fn close(&self) {
+        // hidapi closes the handle on drop, this just stops further use
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn info(&self) -> TransportInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+/// One end of an in-memory connection; whatever is written to one end is read
+/// from the other. Closing either end closes both.
+pub struct LoopbackTransport {
+    name: String,
+    outbound: Mutex<Sender<Vec<u8>>>,
+    inbound: Mutex<Receiver<Vec<u8>>>,
+    closed: Arc<AtomicBool>,
+}
+
+#[cfg(test)]
+impl LoopbackTransport {
+    /// Returns the host end and the device end.
+    pub fn pair() -> (LoopbackTransport, LoopbackTransport) {
+        let (to_device, from_host) = channel();
+        let (to_host, from_device) = channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let host = LoopbackTransport {
+            name: "loopback-host".to_string(),
+            outbound: Mutex::new(to_device),
+            inbound: Mutex::new(from_device),
+            closed: closed.clone(),
+        };
+        let device = LoopbackTransport {
+            name: "loopback-device".to_string(),
+            outbound: Mutex::new(to_host),
+            inbound: Mutex::new(from_host),
+            closed,
+        };
+        (host, device)
+    }
+
+    fn check_open(&self) -> Result<(), HidError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(HidError::new("Loopback is closed!".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Transport for LoopbackTransport {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        self.check_open()?;
+        let inbound = self.inbound.lock().unwrap();
+        let report = if timeout < 0 {
+            inbound.recv().map_err(|_| HidError::new("Loopback peer is gone!".to_string()))?
+        } else {
+            match inbound.recv_timeout(Duration::from_millis(timeout as u64)) {
+                Ok(report) => report,
+                Err(RecvTimeoutError::Timeout) => return Ok(0),
+                Err(RecvTimeoutError::Disconnected) => return Err(HidError::new("Loopback peer is gone!".to_string())),
+            }
+        };
+        let len = report.len().min(buf.len());
+        buf[..len].copy_from_slice(&report[..len]);
+        Ok(len)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        self.check_open()?;
+        self.outbound.lock().unwrap()
+            .send(data.to_vec())
+            .map_err(|_| HidError::new("Loopback peer is gone!".to_string()))?;
+        Ok(data.len())
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn info(&self) -> TransportInfo {
+        TransportInfo {
+            path: self.name.clone(),
+            vendor_id: 0,
+            product_id: 0,
+            serial_number: None,
+            product: Some("Loopback".to_string()),
+        }
+    }
+}
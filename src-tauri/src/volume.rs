@@ -0,0 +1,221 @@
+use serde::{Serialize, Deserialize};
+use crate::packet::Direction;
+
+// slack for levels that went through float math and land a hair below a step
+const EPSILON: f32 = 0.001;
+
+/// How key presses are spread over the volume range.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Curve {
+    /// Every press adds the same amount.
+    #[default]
+    Linear,
+    /// Every press adds the same number of decibels, `range` being the
+    /// distance from the first step to full volume. Steps get finer towards
+    /// the quiet end, where linear ones feel too coarse.
+    Logarithmic {
+        #[serde(default = "default_range")]
+        range: f32,
+    },
+    /// Each press moves to the next of these levels, the step size is ignored.
+    Table { levels: Vec<f32> },
+}
+
+fn default_range() -> f32 {
+    60.0
+}
+
+impl Curve {
+    /// Level one step away from `current`, `None` when that falls below the
+    /// bottom of the curve.
+    fn step(&self, current: f32, direction: Direction, step: f32) -> Option<f32> {
+        let sign = match direction {
+            Direction::Up => 1.0,
+            Direction::Down => -1.0,
+        };
+        match self {
+            Curve::Linear => {
+                let volume = current + sign * step;
+                (volume >= step - EPSILON).then(|| volume.min(1.0))
+            }
+            Curve::Logarithmic { range } => {
+                let range = range.max(1.0);
+                // position 0.0 is silence, 1.0 is full volume, evenly spaced in dB
+                let position = if current > 0.0 {
+                    (1.0 + 20.0 * current.log10() / range).max(0.0)
+                } else {
+                    0.0
+                };
+                let position = position + sign * step;
+                (position >= step - EPSILON).then(|| 10_f32.powf((position.min(1.0) - 1.0) * range / 20.0))
+            }
+            Curve::Table { levels } if levels.is_empty() => Curve::Linear.step(current, direction, step),
+            Curve::Table { levels } => {
+                let mut levels: Vec<f32> = levels.iter().copied().filter(|l| l.is_finite()).collect();
+                levels.sort_by(|a, b| a.total_cmp(b));
+                match direction {
+                    Direction::Up => levels.iter().copied().find(|l| *l > current + EPSILON).or(levels.last().copied()),
+                    Direction::Down => levels.iter().copied().rev().find(|l| *l < current - EPSILON),
+                }
+            }
+        }
+    }
+}
+
+/// Per key volume behaviour, every field falls back to the old global one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VolumeOptions {
+    /// Percent of the curve per press, `Settings.increment` when unset.
+    #[serde(default)]
+    pub step: Option<i32>,
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+    /// Whether stepping below the bottom of the curve mutes, otherwise the
+    /// level stops at `min`.
+    #[serde(default = "default_snap_to_mute")]
+    pub snap_to_mute: bool,
+}
+
+fn default_max() -> f32 {
+    1.0
+}
+
+fn default_snap_to_mute() -> bool {
+    true
+}
+
+impl Default for VolumeOptions {
+    fn default() -> Self {
+        VolumeOptions {
+            step: None,
+            curve: Curve::default(),
+            min: 0.0,
+            max: default_max(),
+            snap_to_mute: default_snap_to_mute(),
+        }
+    }
+}
+
+/// What a control should be set to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub volume: f32,
+    pub muted: bool,
+}
+
+/// Level after one key press, all volumes between 0.0 and 1.0.
+///
+/// `increment` is the global step in percent, used when the options don't
+/// set one. A forced change jumps straight to `max`, or to the bottom.
+/// `current` should be 0.0 for muted controls so the first press up unmutes.
+pub fn next_volume(current: f32, direction: Direction, force: bool, options: &VolumeOptions, increment: i32) -> Level {
+    let step = options.step.unwrap_or(increment) as f32 * 0.01;
+    let min = options.min.clamp(0.0, 1.0);
+    let max = options.max.clamp(0.0, 1.0).max(min);
+    let volume = match direction {
+        Direction::Up if force => Some(max),
+        Direction::Down if force => None,
+        _ => options.curve.step(current, direction, step),
+    };
+    match volume {
+        Some(volume) => Level { volume: volume.clamp(min, max), muted: false },
+        None => Level { volume: min, muted: options.snap_to_mute },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_level(level: Level, volume: f32, muted: bool) {
+        assert!((level.volume - volume).abs() < 0.0005, "{:?} is not at {}", level, volume);
+        assert_eq!(level.muted, muted, "{:?}", level);
+    }
+
+    fn linear() -> VolumeOptions {
+        VolumeOptions::default()
+    }
+
+    #[test]
+    fn steps_by_the_increment() {
+        assert_level(next_volume(0.5, Direction::Up, false, &linear(), 5), 0.55, false);
+        assert_level(next_volume(0.5, Direction::Down, false, &linear(), 5), 0.45, false);
+        let options = VolumeOptions { step: Some(10), ..linear() };
+        assert_level(next_volume(0.5, Direction::Up, false, &options, 5), 0.6, false);
+    }
+
+    #[test]
+    fn force_jumps_to_the_ends() {
+        assert_level(next_volume(0.3, Direction::Up, true, &linear(), 5), 1.0, false);
+        assert_level(next_volume(0.3, Direction::Down, true, &linear(), 5), 0.0, true);
+    }
+
+    #[test]
+    fn stays_within_range() {
+        assert_level(next_volume(0.98, Direction::Up, false, &linear(), 5), 1.0, false);
+        assert_level(next_volume(1.0, Direction::Up, false, &linear(), 5), 1.0, false);
+        assert_level(next_volume(0.5, Direction::Up, false, &linear(), 500), 1.0, false);
+    }
+
+    #[test]
+    fn mutes_below_one_step() {
+        assert_level(next_volume(0.05, Direction::Down, false, &linear(), 5), 0.0, true);
+        assert_level(next_volume(0.07, Direction::Down, false, &linear(), 5), 0.0, true);
+        // a level a hair below two steps still keeps one
+        assert_level(next_volume(0.0999, Direction::Down, false, &linear(), 5), 0.0499, false);
+        // a muted control starts from silence and comes back at one step
+        assert_level(next_volume(0.0, Direction::Up, false, &linear(), 5), 0.05, false);
+    }
+
+    #[test]
+    fn min_and_max_bound_every_press() {
+        let options = VolumeOptions { min: 0.2, max: 0.8, ..linear() };
+        assert_level(next_volume(0.78, Direction::Up, false, &options, 5), 0.8, false);
+        assert_level(next_volume(0.5, Direction::Up, true, &options, 5), 0.8, false);
+        assert_level(next_volume(0.22, Direction::Down, false, &options, 5), 0.2, false);
+        assert_level(next_volume(0.04, Direction::Down, false, &options, 5), 0.2, true);
+        // a max below min is raised to it
+        let options = VolumeOptions { min: 0.6, max: 0.4, ..linear() };
+        assert_level(next_volume(0.5, Direction::Up, true, &options, 5), 0.6, false);
+    }
+
+    #[test]
+    fn snap_to_mute_off_stops_at_min() {
+        let options = VolumeOptions { min: 0.1, snap_to_mute: false, ..linear() };
+        assert_level(next_volume(0.12, Direction::Down, false, &options, 5), 0.1, false);
+        assert_level(next_volume(0.04, Direction::Down, false, &options, 5), 0.1, false);
+        assert_level(next_volume(0.5, Direction::Down, true, &options, 5), 0.1, false);
+    }
+
+    #[test]
+    fn logarithmic_steps_evenly_in_decibels() {
+        let options = VolumeOptions { curve: Curve::Logarithmic { range: 60.0 }, ..linear() };
+        // 5% of 60 dB is 3 dB
+        let down = next_volume(1.0, Direction::Down, false, &options, 5);
+        assert_level(down, 10_f32.powf(-3.0 / 20.0), false);
+        assert_level(next_volume(down.volume, Direction::Up, false, &options, 5), 1.0, false);
+        // the first step up from silence is 57 dB down
+        let first = next_volume(0.0, Direction::Up, false, &options, 5);
+        assert_level(first, 10_f32.powf(-57.0 / 20.0), false);
+        assert_level(next_volume(first.volume, Direction::Down, false, &options, 5), 0.0, true);
+    }
+
+    #[test]
+    fn table_moves_between_levels() {
+        let options = VolumeOptions { curve: Curve::Table { levels: vec![0.8, 0.1, f32::NAN, 0.5] }, ..linear() };
+        assert_level(next_volume(0.0, Direction::Up, false, &options, 5), 0.1, false);
+        assert_level(next_volume(0.1, Direction::Up, false, &options, 5), 0.5, false);
+        assert_level(next_volume(0.3, Direction::Up, false, &options, 5), 0.5, false);
+        assert_level(next_volume(0.8, Direction::Up, false, &options, 5), 0.8, false);
+        assert_level(next_volume(0.5, Direction::Down, false, &options, 5), 0.1, false);
+        assert_level(next_volume(0.1, Direction::Down, false, &options, 5), 0.0, true);
+
+        let empty = VolumeOptions { curve: Curve::Table { levels: vec![] }, ..linear() };
+        assert_level(next_volume(0.5, Direction::Up, false, &empty, 5), 0.55, false);
+    }
+}
@@ -2,12 +2,16 @@ import { createRouter, createWebHistory } from "vue-router";
 
 import Main from './views/Main.vue';
 import Processes from './views/Processes.vue';
+import Devices from './views/Devices.vue';
+import Diagnostics from './views/Diagnostics.vue';
 
 const router = createRouter({
     history: createWebHistory(),
     routes: [
         { path: '/', name: 'main', component: Main },
         { path: '/processes/:id', name: "processes", component: Processes, props: true },
+        { path: '/devices', name: "devices", component: Devices },
+        { path: '/diagnostics', name: "diagnostics", component: Diagnostics },
     ]
 })
 
@@ -0,0 +1,46 @@
+export type MatchBy = 'executable' | 'path' | 'regex' | 'display_name';
+
+export type GroupMode = 'relative' | 'normalized';
+
+export type TypedTarget =
+    | { kind: 'application', by: MatchBy, pattern: string }
+    | { kind: 'device_master', device: string }
+    | { kind: 'default_output' }
+    | { kind: 'default_input' }
+    | { kind: 'capture_device', device: string }
+    | { kind: 'cycle_output', devices: string[] }
+    | { kind: 'switch_output', device: string }
+    | { kind: 'group', apps: Target[], mode: GroupMode }
+    | { kind: 'foreground_app' }
+    | { kind: 'scene', scene: string };
+
+// plain strings are application names
+export type Target = string | TypedTarget;
+
+export function targetLabel(target: Target | null): string {
+    if (target === null || typeof target === 'string') {
+        return target ?? '';
+    }
+    switch (target.kind) {
+        case 'application':
+            return target.by === 'executable' ? target.pattern : `${target.by}: ${target.pattern}`;
+        case 'device_master':
+            return `Master: ${target.device}`;
+        case 'default_output':
+            return 'Default output';
+        case 'default_input':
+            return 'Default input';
+        case 'capture_device':
+            return `Mic: ${target.device}`;
+        case 'cycle_output':
+            return target.devices.length > 0 ? `Cycle: ${target.devices.join(', ')}` : 'Cycle outputs';
+        case 'switch_output':
+            return `Switch to: ${target.device}`;
+        case 'foreground_app':
+            return 'Focused app';
+        case 'group':
+            return target.apps.map(targetLabel).join(' + ');
+        case 'scene':
+            return `Scene: ${target.scene}`;
+    }
+}
@@ -0,0 +1,17 @@
+export type Curve =
+    | { kind: 'linear' }
+    | { kind: 'logarithmic', range: number }
+    | { kind: 'table', levels: number[] };
+
+// volumes are between 0.0 and 1.0, step is in percent and null means the global increment
+export type VolumeOptions = {
+    step: number | null,
+    curve: Curve,
+    min: number,
+    max: number,
+    snap_to_mute: boolean
+};
+
+export function defaultVolumeOptions(): VolumeOptions {
+    return { step: null, curve: { kind: 'linear' }, min: 0, max: 1, snap_to_mute: true };
+}